@@ -1,16 +1,30 @@
-use std::io::{Cursor, Write};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{BufRead, Read, Write};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use futures::SinkExt;
+use sha1::{Digest, Sha1};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, protocol::Role, Message};
+use platform_dirs::{AppDirs, AppUI};
 use serialport::SerialPortType;
+use chrono::Timelike;
+use log::{error, info, trace, warn};
 use hyper::{
     Request,
     Response,
     Body,
+    Method,
+    StatusCode,
     body::HttpBody,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
 struct Color(f32, f32, f32);
 
 impl Color {
@@ -36,8 +50,77 @@ impl Color {
         }
     }
 
+    pub const fn black() -> Color {
+        Color(0., 0., 0.)
+    }
+
+    pub const fn white() -> Color {
+        Color(1., 1., 1.)
+    }
+
+    pub const fn red() -> Color {
+        Color(1., 0., 0.)
+    }
+
+    pub const fn green() -> Color {
+        Color(0., 1., 0.)
+    }
+
+    pub const fn blue() -> Color {
+        Color(0., 0., 1.)
+    }
+
+    pub const fn yellow() -> Color {
+        Color(1., 1., 0.)
+    }
+
     pub fn as_byte_color(&self) -> (u8, u8, u8) {
-        ((self.0 * 255.0) as u8, (self.1 * 255.0) as u8, (self.2 * 255.0) as u8)
+        (to_byte(self.0), to_byte(self.1), to_byte(self.2))
+    }
+
+    pub fn as_hex(&self) -> String {
+        let (r, g, b) = self.as_byte_color();
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    pub fn as_u16_color(&self) -> (u16, u16, u16) {
+        fn channel(c: f32) -> u16 {
+            (c * 65535.).round().clamp(0., 65535.) as u16
+        }
+        (channel(self.0), channel(self.1), channel(self.2))
+    }
+
+    pub fn mix(&self, other: Color, t: f32) -> Color {
+        Color(self.0 + (other.0 - self.0) * t, self.1 + (other.1 - self.1) * t, self.2 + (other.2 - self.2) * t)
+    }
+
+    #[cfg(test)]
+    pub fn approx_eq(&self, other: &Color, eps: f32) -> bool {
+        (self.0 - other.0).abs() <= eps && (self.1 - other.1).abs() <= eps && (self.2 - other.2).abs() <= eps
+    }
+
+    pub fn with_gamma(&self, gamma: (f32, f32, f32)) -> Color {
+        Color(self.0.max(0.).powf(gamma.0), self.1.max(0.).powf(gamma.1), self.2.max(0.).powf(gamma.2))
+    }
+
+    pub fn dither(&self, error: &mut (f32, f32, f32)) -> Color {
+        fn channel(c: f32, error: &mut f32) -> f32 {
+            let target = c * 255. + *error;
+            let q = target.round().clamp(0., 255.);
+            *error = target - q;
+            q / 255.
+        }
+        Color(channel(self.0, &mut error.0), channel(self.1, &mut error.1), channel(self.2, &mut error.2))
+    }
+}
+
+fn to_byte(c: f32) -> u8 {
+    (c * 255.).round().clamp(0., 255.) as u8
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}, {}, {})", self.as_hex(), self.0, self.1, self.2)
     }
 }
 
@@ -57,37 +140,79 @@ impl std::ops::Add<Color> for Color {
 
 enum ColorFormat {
     GRB,
+    GRB16,
 }
 
 impl ColorFormat {
-    pub fn as_bytes(&self, colors: &[Color]) -> Box<[u8]> {
+    pub fn encode_into(&self, colors: &[Color], buf: &mut Vec<u8>) {
         match self {
             ColorFormat::GRB => {
-                let mut bytes = Vec::with_capacity(colors.len() * 3);
+                buf.reserve(colors.len() * 3);
 
                 for c in colors {
                     let (r, g, b) = c.as_byte_color();
-                    bytes.push(g);
-                    bytes.push(r);
-                    bytes.push(b);
+                    buf.push(g);
+                    buf.push(r);
+                    buf.push(b);
                 }
+            },
+            ColorFormat::GRB16 => {
+                buf.reserve(colors.len() * 6);
 
-                return bytes.into_boxed_slice();
+                for c in colors {
+                    let (r, g, b) = c.as_u16_color();
+                    buf.extend_from_slice(&g.to_le_bytes());
+                    buf.extend_from_slice(&r.to_le_bytes());
+                    buf.extend_from_slice(&b.to_le_bytes());
+                }
             },
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+enum ColorOrder {
+    #[default]
+    GRB,
+    RGB,
+    BRG,
+    RBG,
+    GBR,
+    BGR,
+}
+
+impl ColorOrder {
+    pub fn reorder(&self, c: Color) -> Color {
+        let Color(r, g, b) = c;
+        match self {
+            ColorOrder::GRB => Color(r, g, b),
+            ColorOrder::RGB => Color(g, r, b),
+            ColorOrder::BRG => Color(r, b, g),
+            ColorOrder::RBG => Color(b, r, g),
+            ColorOrder::GBR => Color(b, g, r),
+            ColorOrder::BGR => Color(g, b, r),
+        }
+    }
+}
+
+// Gamma is applied on the host by default and frames are sent with the plain
+// instructions. With firmware_gamma the host sends linear values using the
+// Gamma variants and the controller applies its own correction table.
 enum Instruction<'a> {
     Show,
     Clear,
     SetPixelColor(u16, Color),
     SetPixelColorGamma(u16, Color),
     SetPixels(&'a [Color]),
+    SetPixels16(&'a [Color]),
+    SetPixelsGamma(&'a [Color]),
+    FillRange(u16, u16, Color),
+    Ping,
 }
 
 impl Instruction<'_> {
-    pub fn write(&self, w: &mut dyn Write) -> std::io::Result<()> {
+    pub fn write(&self, w: &mut dyn Write, buf: &mut Vec<u8>) -> std::io::Result<()> {
         match self {
             Instruction::Show => w.write_all(&[0, 0]),
             Instruction::Clear => w.write_all(&[1, 0]),
@@ -102,12 +227,138 @@ impl Instruction<'_> {
                 w.write_all(&[3, 0, i_bytes[0], i_bytes[1], r, g, b])
             },
             Instruction::SetPixels(p) => {
-                w.write_all(&[4, 0])?;
-                w.write_all(&ColorFormat::GRB.as_bytes(p))?;
-                Ok(())
+                buf.clear();
+                buf.extend_from_slice(&[4, 0]);
+                ColorFormat::GRB.encode_into(p, buf);
+                w.write_all(buf)
+            },
+            Instruction::SetPixels16(p) => {
+                buf.clear();
+                buf.extend_from_slice(&[5, 0]);
+                ColorFormat::GRB16.encode_into(p, buf);
+                w.write_all(buf)
             },
+            Instruction::SetPixelsGamma(p) => {
+                buf.clear();
+                buf.extend_from_slice(&[6, 0]);
+                ColorFormat::GRB.encode_into(p, buf);
+                w.write_all(buf)
+            },
+            Instruction::FillRange(start, end, col) => {
+                let start_bytes = start.to_le_bytes();
+                let end_bytes = end.to_le_bytes();
+                let (r, g, b) = col.as_byte_color();
+                w.write_all(&[7, 0, start_bytes[0], start_bytes[1], end_bytes[0], end_bytes[1], r, g, b])
+            },
+            Instruction::Ping => w.write_all(&[8, 0]),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Instruction::Show | Instruction::Clear | Instruction::Ping => 2,
+            Instruction::SetPixelColor(..) | Instruction::SetPixelColorGamma(..) => 7,
+            Instruction::SetPixels(p) | Instruction::SetPixelsGamma(p) => 2 + p.len() * 3,
+            Instruction::FillRange(..) => 9,
+            Instruction::SetPixels16(p) => 2 + p.len() * 6,
+        }
+    }
+}
+
+fn changed_runs(cols: &[Color], sent: &[(u8, u8, u8)]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, (c, s)) in cols.iter().zip(sent).enumerate() {
+        let bytes = c.as_byte_color();
+        if bytes == *s {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, end)) if *end == i && cols[*start].as_byte_color() == bytes => *end += 1,
+            _ => runs.push((i, i + 1)),
+        }
+    }
+    runs
+}
+
+fn write_frame(w: &mut dyn Write, buf: &mut Vec<u8>, cols: &[Color], sent: &mut Vec<(u8, u8, u8)>, force: bool, gamma: bool) -> std::io::Result<bool> {
+    let partial = sent.len() == cols.len();
+    let runs = if partial { changed_runs(cols, sent) } else { Vec::new() };
+    if partial && runs.is_empty() && !force {
+        return Ok(false);
+    }
+
+    let black = Color::black();
+    let pixel_len = Instruction::SetPixelColor(0, black).encoded_len();
+    let fill_len = Instruction::FillRange(0, 0, black).encoded_len();
+    // FillRange has no gamma variant, so gamma frames always set pixels one by one
+    let use_fill = |start: usize, end: usize| !gamma && end - start > 1;
+    let cost: usize = runs.iter()
+        .map(|&(start, end)| if use_fill(start, end) { fill_len } else { (end - start) * pixel_len })
+        .sum();
+
+    if partial && cost < Instruction::SetPixels(cols).encoded_len() {
+        for &(start, end) in &runs {
+            let (first, last) = match (u16::try_from(start), u16::try_from(end)) {
+                (Ok(first), Ok(last)) => (first, last),
+                _ => {
+                    warn!("Skipping out of range pixels {}..{}", start, end);
+                    continue;
+                },
+            };
+            if use_fill(start, end) {
+                Instruction::FillRange(first, last, cols[start]).write(w, buf)?;
+            } else {
+                for index in first..last {
+                    let c = cols[index as usize];
+                    if gamma {
+                        Instruction::SetPixelColorGamma(index, c).write(w, buf)?;
+                    } else {
+                        Instruction::SetPixelColor(index, c).write(w, buf)?;
+                    }
+                }
+            }
+            let bytes = cols[start].as_byte_color();
+            for s in &mut sent[start..end] {
+                *s = bytes;
+            }
+        }
+    } else {
+        if gamma {
+            Instruction::SetPixelsGamma(cols).write(w, buf)?;
+        } else {
+            Instruction::SetPixels(cols).write(w, buf)?;
+        }
+        sent.clear();
+        sent.extend(cols.iter().map(Color::as_byte_color));
+    }
+
+    Instruction::Show.write(w, buf)?;
+    Ok(true)
+}
+
+fn send_frame(w: &mut dyn Write, buf: &mut Vec<u8>, cols: &[Color], sent: &mut Vec<(u8, u8, u8)>, options: WriteOptions, force: bool) -> std::io::Result<bool> {
+    if cols.iter().all(|c| c.as_u16_color() == (0, 0, 0)) {
+        let unchanged = sent.len() == cols.len() && sent.iter().all(|&s| s == (0, 0, 0));
+        if options.diff && unchanged && !force {
+            return Ok(false);
         }
+        sent.clear();
+        sent.resize(cols.len(), (0, 0, 0));
+        Instruction::Clear.write(w, buf)?;
+    } else if options.high_depth {
+        sent.clear();
+        Instruction::SetPixels16(cols).write(w, buf)?;
+    } else if options.diff {
+        return write_frame(w, buf, cols, sent, force, options.firmware_gamma);
+    } else if options.firmware_gamma {
+        sent.clear();
+        Instruction::SetPixelsGamma(cols).write(w, buf)?;
+    } else {
+        sent.clear();
+        Instruction::SetPixels(cols).write(w, buf)?;
     }
+    Instruction::Show.write(w, buf)?;
+    Ok(true)
 }
 
 #[derive(serde::Deserialize)]
@@ -132,12 +383,76 @@ struct MapState {
     pub num_matches_to_win_series: i32,
     pub phase: String,
     pub round: i32,
-    pub round_wins: Option<HashMap<String, String>>,
+    #[serde(default, deserialize_with = "deserialize_round_wins")]
+    pub round_wins: Vec<RoundWin>,
     pub souvenirs_total: i32,
     pub team_ct: TeamInfo,
     pub team_t: TeamInfo,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RoundWinKind {
+    Elimination,
+    Bomb,
+    Defuse,
+    Time,
+    Other,
+}
+
+impl RoundWinKind {
+    pub fn parse(reason: &str) -> RoundWinKind {
+        match reason {
+            "elimination" => RoundWinKind::Elimination,
+            "bomb" => RoundWinKind::Bomb,
+            "defuse" => RoundWinKind::Defuse,
+            "time" => RoundWinKind::Time,
+            _ => RoundWinKind::Other,
+        }
+    }
+
+    pub fn color(self, team: Team) -> Color {
+        match self {
+            RoundWinKind::Bomb => team.color().mix(palette::BOMB, 0.4),
+            RoundWinKind::Defuse => team.color().mix(Color::white(), 0.3),
+            RoundWinKind::Time => 0.6 * team.color(),
+            RoundWinKind::Elimination | RoundWinKind::Other => team.color(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RoundWin {
+    pub round: u32,
+    pub team: Team,
+    pub kind: RoundWinKind,
+}
+
+impl RoundWin {
+    pub fn parse(round: &str, result: &str) -> Option<RoundWin> {
+        let (team, reason) = if let Some(reason) = result.strip_prefix("ct_win_") {
+            (Team::CT, reason)
+        } else if let Some(reason) = result.strip_prefix("t_win_") {
+            (Team::T, reason)
+        } else {
+            return None;
+        };
+        Some(RoundWin {
+            round: round.parse().ok()?,
+            team,
+            kind: RoundWinKind::parse(reason),
+        })
+    }
+}
+
+fn deserialize_round_wins<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<RoundWin>, D::Error> {
+    let wins: Option<HashMap<String, String>> = serde::Deserialize::deserialize(deserializer)?;
+    let mut wins: Vec<RoundWin> = wins.unwrap_or_default().iter()
+        .filter_map(|(round, result)| RoundWin::parse(round, result))
+        .collect();
+    wins.sort_by_key(|win| win.round);
+    Ok(wins)
+}
+
 #[derive(serde::Deserialize)]
 struct MatchStats {
     pub assists: i32,
@@ -151,6 +466,7 @@ struct MatchStats {
 struct PlayerState {
     pub armor: f32,
     pub burning: f32,
+    pub defusekit: Option<bool>,
     pub equip_value: i32,
     pub flashed: f32,
     pub health: f32,
@@ -161,6 +477,21 @@ struct PlayerState {
     pub smoked: f32,
 }
 
+impl PlayerState {
+    // Malformed payloads can carry anything, so keep the HUD inputs in range
+    pub fn health_fraction(&self) -> f32 {
+        (self.health / 100.).clamp(0., 1.)
+    }
+
+    pub fn armor_fraction(&self) -> f32 {
+        (self.armor / 100.).clamp(0., 1.)
+    }
+
+    pub fn flash_amount(&self) -> f32 {
+        self.flashed.clamp(0., 255.)
+    }
+}
+
 #[derive(Clone, serde::Deserialize)]
 struct Weapon {
     pub ammo_clip: Option<i32>,
@@ -172,6 +503,37 @@ struct Weapon {
     pub r#type: String,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WeaponCategory {
+    Knife,
+    Pistol,
+    Smg,
+    Rifle,
+    Sniper,
+    Shotgun,
+    Machinegun,
+    Grenade,
+    C4,
+    Other,
+}
+
+impl Weapon {
+    pub fn category(&self) -> WeaponCategory {
+        match self.r#type.as_str() {
+            "Knife" => WeaponCategory::Knife,
+            "Pistol" => WeaponCategory::Pistol,
+            "Submachine Gun" => WeaponCategory::Smg,
+            "Rifle" => WeaponCategory::Rifle,
+            "SniperRifle" => WeaponCategory::Sniper,
+            "Shotgun" => WeaponCategory::Shotgun,
+            "Machine Gun" => WeaponCategory::Machinegun,
+            "Grenade" => WeaponCategory::Grenade,
+            "C4" => WeaponCategory::C4,
+            _ => WeaponCategory::Other,
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 struct Player {
     pub activity: String,
@@ -179,6 +541,7 @@ struct Player {
     pub match_stats: Option<MatchStats>,
     pub name: String,
     pub observer_slot: Option<i32>,
+    pub position: Option<String>,
     pub state: Option<PlayerState>,
     pub steamid: String,
     pub team: Option<String>,
@@ -194,6 +557,22 @@ struct ProviderState {
     pub version: i32,
 }
 
+#[derive(serde::Deserialize)]
+struct BombInfo {
+    pub state: String,
+    pub player: Option<String>,
+    pub countdown: Option<serde_json::Value>,
+}
+
+impl BombInfo {
+    pub fn countdown(&self) -> Option<f32> {
+        match self.countdown.as_ref()? {
+            serde_json::Value::String(s) => s.parse().ok(),
+            v => v.as_f64().map(|v| v as f32),
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 struct RoundState {
     pub bomb: Option<String>,
@@ -205,14 +584,50 @@ struct RoundState {
 #[serde(default)]
 struct GameState {
     pub auth: Option<AuthState>,
+    pub bomb: Option<BombInfo>,
     pub map: Option<MapState>,
     pub player: Option<Player>,
     pub provider: Option<ProviderState>,
     pub round: Option<RoundState>,
     pub previously: Option<HashMap<String, serde_json::Value>>,
+    pub added: Option<HashMap<String, serde_json::Value>>,
+    #[serde(skip)]
+    pub received: Option<Instant>,
+    #[serde(skip)]
+    pub recent_events: Vec<EventType>,
 }
 
 impl GameState {
+    pub fn is_spectating(&self) -> bool {
+        match (&self.player, &self.provider) {
+            (Some(player), Some(provider)) => player.observer_slot.is_some() && player.steamid != provider.steamid,
+            _ => false,
+        }
+    }
+
+    pub fn team(&self) -> Option<Team> {
+        self.player.as_ref().and_then(|player| player.team.as_deref()).and_then(Team::parse)
+    }
+
+    pub fn win_streak(&self) -> u32 {
+        let team = match self.team() {
+            Some(team) => team,
+            None => return 0,
+        };
+        match &self.map {
+            Some(map) => map.round_wins.iter().rev().take_while(|win| win.team == team).count() as u32,
+            None => 0,
+        }
+    }
+
+    pub fn only_knife(&self) -> bool {
+        match self.player.as_ref().and_then(|player| player.weapons.as_ref()) {
+            Some(weapons) => weapons.values().any(|w| w.category() == WeaponCategory::Knife)
+                && weapons.values().all(|w| matches!(w.category(), WeaponCategory::Knife | WeaponCategory::C4)),
+            None => false,
+        }
+    }
+
     pub fn active_weapon(&self) -> Option<(&str, &Weapon)> {
         if let Some(player) = &self.player {
             if let Some(weapons) = &player.weapons {
@@ -226,33 +641,68 @@ impl GameState {
         }
         None
     }
+
+    pub fn bomb_carrier(&self) -> Option<BombCarrier> {
+        let player = self.player.as_ref()?;
+
+        if let Some(weapons) = &player.weapons {
+            for w in weapons.values() {
+                if w.category() == WeaponCategory::C4 {
+                    if w.state == "active" {
+                        return Some(BombCarrier::Holding);
+                    }
+                    return Some(BombCarrier::Carrying);
+                }
+            }
+        }
+
+        if let Some(bomb) = &self.bomb {
+            if bomb.state == "carried" && player.team.as_deref() == Some("T") && bomb.player.as_deref() != Some(player.steamid.as_str()) {
+                return Some(BombCarrier::Teammate);
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BombCarrier {
+    Holding,
+    Carrying,
+    Teammate,
 }
 
 impl Default for GameState {
     fn default() -> GameState {
         GameState {
             auth: None,
+            bomb: None,
             map: None,
             player: None,
             provider: None,
             round: None,
             previously: None,
+            added: None,
+            received: None,
+            recent_events: Vec::new(),
         }
     }
 }
 
 fn clear(cols: &mut [Color]) {
     for i in 0..cols.len() {
-        cols[i] = Color(0., 0., 0.);
+        cols[i] = Color::black();
     }
 }
 
-fn fill(cols: &mut [Color], col: Color, alpha: f32) {
-    for i in 0..cols.len() {
-        cols[i] = (1. - alpha) * cols[i] + alpha * col;
+fn fill(cols: &mut [Color], col: Color, alpha: f32, blend: BlendMode) {
+    for c in cols.iter_mut() {
+        *c = blend.blend(c, &col, alpha);
     }
 }
 
+#[derive(Clone, Copy, serde::Deserialize)]
 enum BlendMode {
     Replace,
     Mix,
@@ -270,12 +720,62 @@ impl BlendMode {
 }
 
 fn draw_line(cols: &mut [Color], from: f32, to: f32, col: Color, blend: BlendMode) {
-    for i in usize::max(f32::floor(from) as usize, 0) .. usize::min(f32::ceil(to) as usize, cols.len()) {
-        let amt = f32::min(f32::max(i as f32 + 1.0 - from, 0.0), 1.0)
-            + f32::min(f32::max(to - i as f32, 0.0), 1.0)
-            - 1.0;
+    if to <= from {
+        return;
+    }
+
+    let start = f32::max(f32::floor(from), 0.) as usize;
+    let end = usize::min(f32::ceil(to) as usize, cols.len());
+    for (i, c) in cols.iter_mut().enumerate().take(end).skip(start) {
+        let amt = ((i as f32 + 1.0 - from).clamp(0.0, 1.0)
+            + (to - i as f32).clamp(0.0, 1.0)
+            - 1.0).clamp(0.0, 1.0);
+
+        *c = blend.blend(c, &col, amt);
+    }
+}
+
+fn draw_bar(cols: &mut [Color], amount: f32, anchor: BarAnchor, col: Color, blend: BlendMode) {
+    let len = cols.len() as f32;
+    let size = len * amount;
+    let (from, to) = match anchor {
+        BarAnchor::Start => (0., size),
+        BarAnchor::End => (len - size, len),
+        BarAnchor::Center => ((len - size) / 2., (len + size) / 2.),
+    };
+    draw_line(cols, from, to, col, blend);
+}
+
+fn draw_line_wrapped(cols: &mut [Color], from: f32, to: f32, col: Color, blend: BlendMode) {
+    let len = cols.len() as f32;
+    if len == 0. || to <= from {
+        return;
+    }
+
+    let start = from.rem_euclid(len);
+    let end = start + (to - from).min(len);
+    draw_line(cols, start, end.min(len), col, blend);
+    if end > len {
+        draw_line(cols, 0., end - len, col, blend);
+    }
+}
+
+fn mirror(cols: &mut [Color]) {
+    let len = cols.len();
+    let half = len.div_ceil(2);
+    cols.copy_within(0..half, len / 2);
+    for i in 0..half {
+        cols[(len - 1) / 2 - i] = cols[len / 2 + i];
+    }
+}
 
-        cols[i] = blend.blend(&cols[i], &col, amt);
+// When mirroring, the effect is expected to be rendered into the first half
+fn orient(frame: &mut [Color], mirrored: bool, reversed: bool) {
+    if mirrored {
+        mirror(frame);
+    }
+    if reversed {
+        frame.reverse();
     }
 }
 
@@ -284,346 +784,4588 @@ fn merge(a: &mut serde_json::Value, b: serde_json::Value) {
         (a @ &mut serde_json::Value::Object(_), serde_json::Value::Object(b)) => {
             let a = a.as_object_mut().unwrap();
             for (k,v) in b {
-                merge(a.entry(k).or_insert(serde_json::Value::Null), v.clone());
+                merge(a.entry(k).or_insert(serde_json::Value::Null), v);
             }
         },
         (a, b) => *a = b,
     }
 }
 
-async fn handle_http(mut req: Request<Body>, state: Arc<Mutex<GameState>>, next_event: Arc<Mutex<Vec<EventType>>>) -> Result<Response<Body>, std::convert::Infallible> {
-    let mut bytes = Vec::with_capacity(req.body().size_hint().lower() as usize);
-    loop {
-        if let Some(Ok(data)) = req.body_mut().data().await {
-            bytes.extend_from_slice(&*data);
-        } else {
-            break;
-        }
-    }
-
-    //let value: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(Cursor::new(bytes))).unwrap();
-    {
-        let mut guard = state.lock().unwrap();
-        *guard = serde_json::from_reader(std::io::BufReader::new(Cursor::new(bytes))).unwrap();
-
-        if let Some(map) = &(*guard).previously {
-            if let Some(player) = &(*guard).player {
-                if let Some(prev_player) = map.get("player") {
-                    let prev_steamid = prev_player.get("steamid");
-                    if prev_steamid.is_none() || prev_steamid.unwrap().as_str().unwrap() == player.steamid {
-                        if let Some((k, w)) = (*guard).active_weapon() {
-                            if let Some(prev_weapons) = prev_player.get("weapons") {
-                                if let Some(prev_weapon) = prev_weapons.get(k) {
-                                    if if let Some(prev_state) = prev_weapon.get("state") {
-                                        if prev_state == "holstered" {
-                                            next_event.lock().unwrap().push(EventType::SwitchWeapon);
-                                            false
-                                        } else {
-                                            true
-                                        }
+// Removed fields only show up in previously, so drop anything listed there that
+// the new body no longer has
+fn prune(a: &mut serde_json::Value, previously: &serde_json::Value, body: &serde_json::Value) {
+    let (a, previously) = match (a.as_object_mut(), previously.as_object()) {
+        (Some(a), Some(previously)) => (a, previously),
+        _ => return,
+    };
+    for (k, v) in previously {
+        match body.get(k) {
+            None => {
+                a.remove(k);
+            },
+            Some(body) => if let Some(a) = a.get_mut(k) {
+                prune(a, v, body);
+            },
+        }
+    }
+}
+
+fn merge_update(merged: &mut serde_json::Value, mut value: serde_json::Value) -> serde_json::Value {
+    // previously and added describe only this update, so they are never merged
+    let diff: Vec<(&str, serde_json::Value)> = ["previously", "added"].iter()
+        .filter_map(|&k| Some((k, value.as_object_mut()?.remove(k)?)))
+        .collect();
+    if let Some((_, previously)) = diff.iter().find(|(k, _)| *k == "previously") {
+        prune(merged, previously, &value);
+    }
+    merge(merged, value);
+    let mut value = merged.clone();
+    if let Some(object) = value.as_object_mut() {
+        for (k, v) in diff {
+            object.insert(k.to_string(), v);
+        }
+    }
+    value
+}
+
+struct LightsStatus {
+    pub enabled: AtomicBool,
+    pub connected: AtomicBool,
+}
+
+struct StatePublisher {
+    pub state: watch::Sender<Arc<GameState>>,
+    pub latest: watch::Receiver<Arc<GameState>>,
+    pub events: mpsc::UnboundedSender<EventType>,
+    pub merged: Mutex<serde_json::Value>,
+}
+
+fn update_state(bytes: &[u8], config: &Mutex<Config>, publisher: &StatePublisher) {
+    let mut value: serde_json::Value = match serde_json::from_slice(bytes) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("Failed to parse game state: {}", e);
+            return;
+        },
+    };
+
+    let (merge_updates, primary_provider, appid_allowed) = {
+        let config = config.lock().unwrap();
+        let appid = value["provider"]["appid"].as_i64();
+        let allowed = appid.is_some_and(|appid| config.appids.iter().any(|&a| i64::from(a) == appid));
+        (config.merge_updates, config.primary_provider.clone(), allowed)
+    };
+
+    if let Some(primary) = &primary_provider {
+        let provider = &value["provider"];
+        let matches = |key: &str| provider[key].as_str() == Some(primary.as_str());
+        if !matches("steamid") && !matches("name") {
+            trace!("Ignoring update from non-primary provider {}", provider["steamid"]);
+            return;
+        }
+    }
+    // Checked on the raw body, so a rejected update never reaches the merged state
+    if !appid_allowed {
+        warn!("Ignoring update from unexpected appid {}", value["provider"]["appid"]);
+        return;
+    }
+
+    if merge_updates {
+        value = merge_update(&mut publisher.merged.lock().unwrap(), value);
+    }
+
+    let mut new_state: GameState = match serde_json::from_value(value) {
+        Ok(new_state) => new_state,
+        Err(e) => {
+            warn!("Failed to parse game state: {}", e);
+            return;
+        },
+    };
+    new_state.received = Some(Instant::now());
+
+    let mut events = Vec::new();
+    let mut state = GameState::default();
+    apply_update(&mut state, new_state, &mut events);
+
+    let mut recent = publisher.latest.borrow().recent_events.clone();
+    recent.extend(events.iter().copied());
+    if recent.len() > RECENT_EVENTS {
+        recent.drain(..recent.len() - RECENT_EVENTS);
+    }
+    state.recent_events = recent;
+
+    let _ = publisher.state.broadcast(Arc::new(state));
+    for e in events {
+        let _ = publisher.events.send(e);
+    }
+}
+
+fn push_event(events: &mut Vec<EventType>, event: EventType) {
+    if event.is_transient() && events.contains(&event) {
+        return;
+    }
+    events.push(event);
+}
+
+fn apply_update(state: &mut GameState, new: GameState, events: &mut Vec<EventType>) {
+    *state = new;
+
+    if let Some(map) = &state.previously {
+        if let Some(player) = &state.player {
+            if let Some(prev_player) = map.get("player") {
+                // A changed steamid means the diff describes another player (switching
+                // spectator target or reconnecting), so only this update is skipped and the
+                // next one diffs against the new player as usual
+                let changed_from = prev_player.get("steamid")
+                    .and_then(|v| v.as_str())
+                    .filter(|&steamid| steamid != player.steamid);
+                if let Some(prev_steamid) = changed_from {
+                    info!("Player changed from {} to {}", prev_steamid, player.steamid);
+                } else {
+                    if let Some((k, w)) = state.active_weapon() {
+                        if let Some(prev_weapons) = prev_player.get("weapons") {
+                            if let Some(prev_weapon) = prev_weapons.get(k) {
+                                if if let Some(prev_state) = prev_weapon.get("state") {
+                                    if prev_state == "holstered" {
+                                        push_event(events, EventType::SwitchWeapon);
+                                        false
                                     } else {
                                         true
-                                    } {
-                                        if let Some(ammo_clip) = w.ammo_clip {
-                                            if let Some(prev_ammo) = prev_weapon.get("ammo_clip") {
-                                                if ammo_clip < prev_ammo.as_i64().unwrap() as i32 {
-                                                    next_event.lock().unwrap().push(EventType::Shoot);
-                                                }
+                                    }
+                                } else {
+                                    true
+                                } {
+                                    if let Some(ammo_clip) = w.ammo_clip {
+                                        if let Some(prev_ammo) = prev_weapon.get("ammo_clip").and_then(|v| v.as_i64()) {
+                                            if ammo_clip < prev_ammo as i32 {
+                                                push_event(events, EventType::Shoot);
                                             }
                                         }
                                     }
                                 }
                             }
                         }
-    
-                        if let Some(prev_state) = prev_player.get("state") {
-                            if let Some(state) = &player.state {
-                                if let Some(prev_health) = prev_state.get("health") {
-                                    if state.health == 0. && prev_health.as_f64().unwrap() != 0. {
-                                        next_event.lock().unwrap().push(EventType::Death);
-                                    }
+                    }
+
+                    if let Some(prev_state) = prev_player.get("state") {
+                        if let Some(state) = &player.state {
+                            if let Some(prev_health) = prev_state.get("health").and_then(|v| v.as_f64()) {
+                                if state.health == 0. && prev_health != 0. {
+                                    push_event(events, EventType::Death);
+                                } else if (state.health as f64) < prev_health {
+                                    push_event(events, EventType::TookDamage((prev_health - state.health as f64).round() as u32));
                                 }
                             }
                         }
-    
-                        if let Some(prev_stats) = prev_player.get("match_stats") {
-                            if let Some(stats) = &player.match_stats {
-                                if let Some(prev_mvps) = prev_stats.get("mvps") {
-                                    if stats.mvps > prev_mvps.as_i64().unwrap() as i32 {
-                                        next_event.lock().unwrap().push(EventType::MVP);
-                                    }
+                    }
+
+                    if let Some(prev_stats) = prev_player.get("match_stats") {
+                        if let Some(stats) = &player.match_stats {
+                            if let Some(prev_mvps) = prev_stats.get("mvps").and_then(|v| v.as_i64()) {
+                                if stats.mvps > prev_mvps as i32 {
+                                    push_event(events, EventType::MVP);
                                 }
+                            }
 
-                                if let Some(prev_kills) = prev_stats.get("kills") {
-                                    if stats.kills > prev_kills.as_i64().unwrap() as i32 {
-                                        if let Some((_, w)) = (*guard).active_weapon() {
-                                            if w.r#type == "Knife" {
-                                                next_event.lock().unwrap().push(EventType::KnifeKill);
-                                            } else {
-                                                next_event.lock().unwrap().push(EventType::Kill);
-                                            }
-                                        } else {
-                                            next_event.lock().unwrap().push(EventType::Kill);
-                                        }
-                                    }
+                            if let Some(prev_kills) = prev_stats.get("kills").and_then(|v| v.as_i64()) {
+                                let knife = state.active_weapon().is_some_and(|(_, w)| w.category() == WeaponCategory::Knife);
+                                for _ in prev_kills as i32..stats.kills {
+                                    push_event(events, if knife { EventType::KnifeKill } else { EventType::Kill });
                                 }
                             }
                         }
                     }
                 }
             }
+        }
 
-            if let Some(round) = &(*guard).round {
-                if let Some(prev_round) = map.get("round") {
-                    if let Some(prev_phase) = prev_round.get("phase") {
-                        if round.phase == "freezetime" && prev_phase.as_str().unwrap() == "over" {
-                            next_event.lock().unwrap().push(EventType::NewRound);
-                        }
+        if let Some(round) = &state.round {
+            if let Some(prev_round) = map.get("round") {
+                if let Some(prev_phase) = prev_round.get("phase").and_then(|v| v.as_str()) {
+                    if round.phase == "freezetime" && prev_phase == "over" {
+                        push_event(events, EventType::NewRound);
                     }
                 }
             }
         }
-
     }
 
-    let response = Response::new(Body::empty());
-    Ok(response)
-}
+    if let Some(round) = &state.round {
+        if let Some(bomb) = &round.bomb {
+            // CS2 reports a newly appeared bomb field under "added" rather than "previously"
+            let prev_bomb = state.previously.as_ref()
+                .and_then(|p| p.get("round"))
+                .and_then(|r| r.get("bomb"))
+                .and_then(|v| v.as_str());
+            let bomb_added = state.added.as_ref()
+                .and_then(|a| a.get("round"))
+                .and_then(|r| r.get("bomb"))
+                .is_some();
 
-fn do_rainbow(cols: &mut [Color], time: f64, cycle_time: f64, alpha: f32) {
-    let cycle = (((time / cycle_time) % 1. + 1.) % 1.) as f32;
-    let len = cols.len();
-    for i in 0..len {
-        cols[i] = (1. - alpha) * cols[i] + alpha * Color::from_hue(cycle - (i as f32 / len as f32));
+            if bomb_added || prev_bomb.is_some_and(|b| b != bomb) {
+                match bomb.as_str() {
+                    "defused" => push_event(events, EventType::BombDefused),
+                    "exploded" => push_event(events, EventType::BombExploded),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    if bomb_planted(state) {
+        push_event(events, EventType::BombPlanted);
     }
 }
 
-type Event = (EventType, f64);
+fn bomb_planted(state: &GameState) -> bool {
+    let round = match &state.round {
+        Some(round) => round,
+        None => return false,
+    };
+    let prev_round = state.previously.as_ref().and_then(|p| p.get("round"));
+    let added_round = state.added.as_ref().and_then(|a| a.get("round"));
 
-#[derive(Clone, Copy, Debug)]
-enum EventType {
-    Shoot,
-    Kill,
-    KnifeKill,
-    SwitchWeapon,
-    Death,
-    MVP,
-    NewRound,
+    let bomb_changed = round.bomb.as_deref() == Some("planted") && (
+        added_round.and_then(|r| r.get("bomb")).is_some()
+        || prev_round.and_then(|r| r.get("bomb")).and_then(|v| v.as_str()).is_some_and(|b| b != "planted")
+    );
+    let phase_changed = (round.phase == "bomb" || round.phase == "planted")
+        && prev_round.and_then(|r| r.get("phase")).and_then(|v| v.as_str()).is_some_and(|p| p != round.phase);
+
+    bomb_changed || phase_changed
 }
 
-static COLOR_CT: Color = Color(0.1, 0.3, 1.0);
-static COLOR_T: Color = Color(1.0, 0.5, 0.1);
+#[derive(serde::Serialize)]
+struct Status<'a> {
+    pub map_phase: Option<&'a str>,
+    pub round_phase: Option<&'a str>,
+    pub score_ct: Option<i32>,
+    pub score_t: Option<i32>,
+    pub team: Option<&'a str>,
+    pub health: Option<f32>,
+    pub armor: Option<f32>,
+    pub ammo_clip: Option<i32>,
+    pub ammo_clip_max: Option<i32>,
+    pub last_events: &'a [EventType],
+}
 
-fn do_lights(serial: &str, state: Arc<Mutex<GameState>>, next_event: Arc<Mutex<Vec<EventType>>>) {
-    let start = Instant::now();
-    let mut knife_start = Instant::now();
+impl Status<'_> {
+    pub fn from_state(state: &GameState) -> Status<'_> {
+        let player_state = state.player.as_ref().and_then(|p| p.state.as_ref());
+        let weapon = state.active_weapon().map(|(_, w)| w);
 
-    let mut last_event: Option<Event> = None;
-    let mut kill_event: Option<Event> = None;
+        Status {
+            map_phase: state.map.as_ref().map(|m| m.phase.as_str()),
+            round_phase: state.round.as_ref().map(|r| r.phase.as_str()),
+            score_ct: state.map.as_ref().map(|m| m.team_ct.score),
+            score_t: state.map.as_ref().map(|m| m.team_t.score),
+            team: state.player.as_ref().and_then(|p| p.team.as_deref()),
+            health: player_state.map(|s| s.health),
+            armor: player_state.map(|s| s.armor),
+            ammo_clip: weapon.and_then(|w| w.ammo_clip),
+            ammo_clip_max: weapon.and_then(|w| w.ammo_clip_max),
+            last_events: &state.recent_events,
+        }
+    }
+}
 
-    let mut serial = serialport::open_with_settings(serial, &serialport::SerialPortSettings {
-        baud_rate: 250000,
-        data_bits: serialport::DataBits::Eight,
-        flow_control: serialport::FlowControl::None,
-        parity: serialport::Parity::None,
-        stop_bits: serialport::StopBits::One,
-        timeout: std::time::Duration::from_millis(100),
-    }).expect("Failed to open serial port");
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
 
-    let mut cols = [Color(0.0, 0.0, 1.0); 60];
-    let s = &mut serial;
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            empty_response(StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    }
+}
 
-    let mut mvp = false;
+fn websocket_accept(headers: &hyper::HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|v: &hyper::header::HeaderValue| v.to_str().ok());
+    let upgrade = header(hyper::header::UPGRADE)?;
+    if !upgrade.split(',').any(|p| p.trim().eq_ignore_ascii_case("websocket")) {
+        return None;
+    }
+    if header(hyper::header::SEC_WEBSOCKET_VERSION)? != "13" {
+        return None;
+    }
+    let key = headers.get(hyper::header::SEC_WEBSOCKET_KEY)?;
+
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(WEBSOCKET_GUID);
+    Some(base64::encode(sha.finalize()))
+}
+
+fn preview_handshake(req: Request<Body>, frames: watch::Receiver<Vec<u8>>) -> Response<Body> {
+    let accept = match websocket_accept(req.headers()) {
+        Some(accept) => accept,
+        None => return empty_response(StatusCode::BAD_REQUEST),
+    };
+
+    tokio::spawn(async move {
+        match req.into_body().on_upgrade().await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                if let Err(e) = stream_preview(ws, frames).await {
+                    info!("Preview client disconnected: {}", e);
+                }
+            },
+            Err(e) => warn!("Preview upgrade failed: {}", e),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::UPGRADE, "websocket")
+        .header(hyper::header::CONNECTION, "Upgrade")
+        .header(hyper::header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn stream_preview(mut ws: WebSocketStream<hyper::upgrade::Upgraded>, mut frames: watch::Receiver<Vec<u8>>) -> Result<(), tungstenite::Error> {
+    while let Some(frame) = frames.recv().await {
+        // The channel starts out empty before the first frame is rendered
+        if frame.is_empty() {
+            continue;
+        }
+        ws.send(Message::Binary(frame)).await?;
+    }
+    Ok(())
+}
+
+async fn handle_http(mut req: Request<Body>, config: Arc<Mutex<Config>>, publisher: Arc<StatePublisher>, recorder: Option<mpsc::UnboundedSender<Vec<u8>>>, preview: Option<watch::Receiver<Vec<u8>>>, status: Arc<LightsStatus>) -> Result<Response<Body>, std::convert::Infallible> {
+    if req.method() == Method::POST && req.uri().path() == "/power" {
+        let on = match req.uri().query() {
+            Some("on") => true,
+            Some("off") => false,
+            _ => !status.enabled.load(Ordering::SeqCst),
+        };
+        status.enabled.store(on, Ordering::SeqCst);
+        info!("Lights turned {}", if on { "on" } else { "off" });
+        return Ok(json_response(&serde_json::json!({ "enabled": on })));
+    }
+
+    if req.method() == Method::GET {
+        let response = match (req.uri().path(), preview) {
+            ("/status", _) => json_response(&Status::from_state(&publisher.latest.borrow())),
+            ("/healthz", _) => json_response(&serde_json::json!({
+                "connected": status.connected.load(Ordering::SeqCst),
+                "enabled": status.enabled.load(Ordering::SeqCst),
+                "last_update_secs": publisher.latest.borrow().received.map(|received| received.elapsed().as_secs_f64()),
+            })),
+            ("/preview", Some(frames)) => preview_handshake(req, frames),
+            _ => empty_response(StatusCode::NOT_FOUND),
+        };
+        return Ok(response);
+    }
+
+    let max_body_size = config.lock().unwrap().max_body_size;
+    let size_hint = req.body().size_hint().lower() as usize;
+    if size_hint > max_body_size {
+        warn!("Rejecting request body of {} bytes", size_hint);
+        return Ok(empty_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    let mut bytes = Vec::with_capacity(size_hint);
+    while let Some(Ok(data)) = req.body_mut().data().await {
+        if bytes.len() + data.len() > max_body_size {
+            warn!("Rejecting request body larger than {} bytes", max_body_size);
+            return Ok(empty_response(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        bytes.extend_from_slice(&data);
+    }
+
+    if let Some(recorder) = &recorder {
+        if recorder.send(bytes.clone()).is_err() {
+            warn!("Recorder stopped, dropping game state");
+        }
+    }
+
+    update_state(&bytes, &config, &publisher);
+
+    let response = Response::new(Body::empty());
+    Ok(response)
+}
+
+async fn record(path: PathBuf, mut bodies: mpsc::UnboundedReceiver<Vec<u8>>) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = std::io::BufWriter::new(file);
+    let mut flush = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            body = bodies.recv() => match body {
+                Some(body) => {
+                    // GSI bodies are pretty-printed, keep each one on a single line
+                    let line: Vec<u8> = body.iter().map(|&b| if b == b'\n' || b == b'\r' { b' ' } else { b }).collect();
+                    writer.write_all(&line)?;
+                    writer.write_all(b"\n")?;
+                },
+                None => break,
+            },
+            _ = flush.tick() => writer.flush()?,
+        }
+    }
+
+    writer.flush()
+}
+
+async fn replay(path: &Path, rate: f64, config: &Mutex<Config>, publisher: &StatePublisher) -> std::io::Result<()> {
+    let file = std::fs::File::open(path)?;
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1. / rate));
+
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        interval.tick().await;
+        update_state(line.as_bytes(), config, publisher);
+    }
+
+    info!("Replay finished");
+    Ok(())
+}
+
+fn ease(current: f32, target: f32, dt: f64, time_constant: f64) -> f32 {
+    if time_constant <= 0. {
+        return target;
+    }
+    current + (target - current) * (1. - (-dt / time_constant).exp()) as f32
+}
+
+fn do_rainbow(cols: &mut [Color], time: f64, cycle_time: f64, alpha: f32) {
+    let cycle = (((time / cycle_time) % 1. + 1.) % 1.) as f32;
+    let len = cols.len();
+    for i in 0..len {
+        cols[i] = (1. - alpha) * cols[i] + alpha * Color::from_hue(cycle - (i as f32 / len as f32));
+    }
+}
+
+fn draw_score(cols: &mut [Color], score_ct: i32, score_t: i32, rounds_to_win: u32) {
+    let len = cols.len() as f32;
+    let half = len / 2.;
+    let ct = (score_ct.max(0) as f32 / rounds_to_win as f32).min(1.) * half;
+    let t = (score_t.max(0) as f32 / rounds_to_win as f32).min(1.) * half;
+    draw_line(cols, 0., ct, palette::CT, BlendMode::Replace);
+    draw_line(cols, len - t, len, palette::T, BlendMode::Replace);
+}
+
+fn draw_series(cols: &mut [Color], map: &MapState, rounds_to_win: u32, time: f64) {
+    let needed = map.num_matches_to_win_series;
+    if needed <= 1 {
+        return;
+    }
+    let len = cols.len();
+    let flash = (time * 4.).fract() < 0.5;
+    for (team, color, from_end) in [(&map.team_ct, palette::CT, false), (&map.team_t, palette::T, true)] {
+        let match_point = team.matches_won_this_series == needed - 1 && team.score == rounds_to_win as i32 - 1;
+        for i in 0..needed as usize {
+            let idx = i * 2;
+            if idx >= len {
+                break;
+            }
+            let idx = if from_end { len - 1 - idx } else { idx };
+            cols[idx] = if match_point && flash {
+                Color::white()
+            } else if (i as i32) < team.matches_won_this_series {
+                color
+            } else {
+                0.1 * color
+            };
+        }
+    }
+}
+
+fn crossfade(cols: &mut [Color], from: &[Color], t: f32) {
+    for (c, f) in cols.iter_mut().zip(from) {
+        *c = (1. - t) * *f + t * *c;
+    }
+}
+
+fn limit_power(cols: &mut [Color], max_total: f32) {
+    let total: f32 = cols.iter().map(|c| c.0 + c.1 + c.2).sum();
+    if total > max_total {
+        let scale = max_total / total;
+        for c in cols.iter_mut() {
+            *c = scale * *c;
+        }
+    }
+}
+
+fn hash(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn random_unit(x: u64) -> f64 {
+    (hash(x) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn twinkle(cols: &mut [Color], time: f64, seed: u64, color: Color, density: f32, decay: f32) {
+    for (i, c) in cols.iter_mut().enumerate() {
+        let pixel = hash(seed ^ i as u64);
+        let t = time + random_unit(pixel);
+        let cycle = t.floor();
+        if random_unit(pixel ^ cycle as u64) < density as f64 {
+            let amt = (-(decay as f64) * (t - cycle)).exp() as f32;
+            *c = *c + amt * color;
+        }
+    }
+}
+
+fn do_chase(cols: &mut [Color], time: f64, speed: f64, spacing: usize, color: Color) {
+    let offset = ((time * speed) % spacing as f64 + spacing as f64) % spacing as f64;
+    for (i, c) in cols.iter_mut().enumerate() {
+        let d = ((i as f64 - offset) % spacing as f64 + spacing as f64) % spacing as f64;
+        let amt = (1. - d.min(spacing as f64 - d)).max(0.);
+        *c = (1. - amt as f32) * *c + amt as f32 * color;
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(tag = "effect", rename_all = "lowercase")]
+enum Reaction {
+    Flash(EventEffect),
+    Rainbow { duration: f64 },
+    Chase,
+    Pulse { color: Color, duration: f64 },
+    #[serde(rename = "none")]
+    Nothing,
+}
+
+impl Reaction {
+    pub fn duration(&self, chase: &KillChase) -> f64 {
+        match self {
+            Reaction::Flash(effect) => effect.duration,
+            Reaction::Rainbow { duration } | Reaction::Pulse { duration, .. } => *duration,
+            Reaction::Chase => chase.lifetime,
+            Reaction::Nothing => 0.,
+        }
+    }
+
+    pub fn speed_key(&self) -> &'static str {
+        match self {
+            Reaction::Flash(_) | Reaction::Nothing => "flash",
+            Reaction::Rainbow { .. } => "rainbow",
+            Reaction::Chase => "kill_chase",
+            Reaction::Pulse { .. } => "pulse",
+        }
+    }
+
+    pub fn draw(&self, cols: &mut [Color], since: f64, time: f64, chase: &KillChase, reverse: bool) {
+        match self {
+            Reaction::Flash(effect) => effect.draw(cols, since),
+            Reaction::Rainbow { duration } => do_rainbow(cols, time, 1., (2. * (1. - since / duration)).clamp(0., 1.) as f32),
+            Reaction::Chase => chase.draw(cols, since, reverse),
+            Reaction::Pulse { color, duration } => {
+                fill(cols, *color, (std::f64::consts::PI * since / duration).sin().max(0.) as f32, BlendMode::Mix);
+            },
+            Reaction::Nothing => (),
+        }
+    }
+}
+
+const MAX_KILL_PULSES: usize = 5;
+
+const WIN_WIPE_TIME: f64 = 0.4;
+
+const MAX_MONEY: f32 = 16000.;
+
+const BOMB_FUSE: f64 = 40.;
+
+const DEFUSE_TIME: f64 = 10.;
+
+const DEFUSE_TIME_KIT: f64 = 5.;
+
+const LOSS_BONUS_LEVELS: usize = 5;
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DefuseHeuristic {
+    Off,
+    Planted,
+    #[default]
+    Stationary,
+}
+
+impl DefuseHeuristic {
+    // GSI only reports bomb state and countdown to observers, so a regular player's defuse
+    // has to be guessed. Planted shows the bar for any living CT while the bomb is down.
+    // Stationary also needs a defuse kit and no position change in the last update, which
+    // only works with the player_position component enabled in the GSI config.
+    pub fn defusing(self, state: &GameState) -> bool {
+        let planted = state.round.as_ref().is_some_and(|round| round.bomb.as_deref() == Some("planted"));
+        let player = match &state.player {
+            Some(player) if planted && player.team.as_deref() == Some("CT") => player,
+            _ => return false,
+        };
+        let alive = player.state.as_ref().is_some_and(|s| s.health > 0.);
+        match self {
+            DefuseHeuristic::Off => false,
+            DefuseHeuristic::Planted => alive,
+            DefuseHeuristic::Stationary => {
+                let kit = player.state.as_ref().and_then(|s| s.defusekit).unwrap_or(false);
+                let moved = state.previously.as_ref()
+                    .and_then(|p| p.get("player"))
+                    .and_then(|p| p.get("position"))
+                    .is_some();
+                alive && kit && player.position.is_some() && !moved
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SpectatorFallback {
+    #[default]
+    Follow,
+    Scoreboard,
+    Idle,
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FreezetimeDisplay {
+    #[default]
+    RoundWins,
+    Scores,
+    Money,
+    LossBonus,
+}
+
+struct ActiveReaction {
+    pub event: Option<EventType>,
+    pub reaction: Reaction,
+    pub start: f64,
+    pub reverse: bool,
+}
+
+impl ActiveReaction {
+    pub fn elapsed(&self, time: f64, config: &Config) -> f64 {
+        (time - self.start) * config.speed(self.reaction.speed_key())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+enum EventType {
+    Shoot,
+    Kill,
+    KnifeKill,
+    SwitchWeapon,
+    Death,
+    MVP,
+    NewRound,
+    BombPlanted,
+    BombDefused,
+    BombExploded,
+    TookDamage(u32),
+}
+
+impl EventType {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, EventType::Shoot | EventType::SwitchWeapon)
+    }
+
+    // Config keys carry no payload, so damage of any amount shares one entry
+    pub fn key(self) -> EventType {
+        match self {
+            EventType::TookDamage(_) => EventType::TookDamage(0),
+            event => event,
+        }
+    }
+}
+
+fn deserialize_reactions<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<HashMap<EventType, Reaction>, D::Error> {
+    use serde::de::IntoDeserializer;
+    let reactions: HashMap<String, Reaction> = serde::Deserialize::deserialize(deserializer)?;
+    reactions.into_iter()
+        .map(|(event, reaction)| {
+            let event = match event.as_str() {
+                "TookDamage" => EventType::TookDamage(0),
+                event => serde::Deserialize::deserialize(event.into_deserializer())?,
+            };
+            Ok((event, reaction))
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+enum EffectShape {
+    #[default]
+    Fill,
+    Collapse,
+    Expand,
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+struct EventEffect {
+    pub color: Color,
+    pub duration: f64,
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub shape: EffectShape,
+}
+
+impl EventEffect {
+    pub fn draw(&self, cols: &mut [Color], since: f64) {
+        let t = (since / self.duration) as f32;
+        let (alpha, sweep) = match self.shape {
+            EffectShape::Fill => ((1. - t).max(0.), 1.),
+            EffectShape::Collapse | EffectShape::Expand => {
+                if t < 0.25 {
+                    (1., t / 0.25)
+                } else {
+                    ((1. - (t - 0.25) / 0.75).max(0.), 1.)
+                }
+            },
+        };
+
+        let len = cols.len() as f32;
+        let reach = len / 2. * sweep;
+        for (i, c) in cols.iter_mut().enumerate() {
+            let x = i as f32 + 0.5;
+            let covered = match self.shape {
+                EffectShape::Fill => true,
+                EffectShape::Collapse => x < reach || x > len - reach,
+                EffectShape::Expand => (x - len / 2.).abs() < reach,
+            };
+            if covered {
+                *c = self.blend.blend(c, &self.color, alpha);
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct EventEffects {
+    pub shoot: EventEffect,
+    pub kill: EventEffect,
+    pub death: EventEffect,
+    pub bomb_defused: EventEffect,
+    pub damage: EventEffect,
+    pub new_round: EventEffect,
+    pub flashbang: Color,
+    pub flashbang_blend: BlendMode,
+    pub shoot_cooldown: f64,
+}
+
+impl EventEffects {
+    pub fn get(&self, event: EventType) -> Option<&EventEffect> {
+        match event {
+            EventType::Shoot => Some(&self.shoot),
+            EventType::Kill => Some(&self.kill),
+            EventType::Death => Some(&self.death),
+            EventType::BombDefused => Some(&self.bomb_defused),
+            EventType::NewRound => Some(&self.new_round),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EventEffects {
+    fn default() -> EventEffects {
+        EventEffects {
+            shoot: EventEffect { color: 0.35 * Color(1., 1., 0.25), duration: 0.125, blend: BlendMode::Add, shape: EffectShape::Fill },
+            kill: EventEffect { color: 0.6 * Color::yellow(), duration: 1., blend: BlendMode::Add, shape: EffectShape::Fill },
+            death: EventEffect { color: Color::red(), duration: 4., blend: BlendMode::Replace, shape: EffectShape::Collapse },
+            bomb_defused: EventEffect { color: palette::CT, duration: 2., blend: BlendMode::Mix, shape: EffectShape::Fill },
+            damage: EventEffect { color: Color::red(), duration: 0.5, blend: BlendMode::Add, shape: EffectShape::Collapse },
+            new_round: EventEffect { color: 0.3 * Color::white(), duration: 0.3, blend: BlendMode::Add, shape: EffectShape::Expand },
+            flashbang: Color::white(),
+            flashbang_blend: BlendMode::Mix,
+            shoot_cooldown: 0.1,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct KillChase {
+    pub enabled: bool,
+    pub color: Color,
+    pub speed: f64,
+    pub lifetime: f64,
+    pub wrap: bool,
+}
+
+impl KillChase {
+    pub fn draw(&self, cols: &mut [Color], since: f64, reverse: bool) {
+        let len = cols.len() as f32;
+        let pos = (since * self.speed) as f32;
+        let pos = if reverse { len - 1. - pos } else { pos };
+        if self.wrap {
+            draw_line_wrapped(cols, pos, pos + 1., self.color, BlendMode::Add);
+        } else {
+            draw_line(cols, pos, pos + 1., self.color, BlendMode::Add);
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct SwitchFlash {
+    pub enabled: bool,
+    pub brightness: f32,
+    pub duration: f64,
+    pub blend: BlendMode,
+}
+
+impl SwitchFlash {
+    pub fn effect(&self, weapon_color: Color) -> EventEffect {
+        EventEffect {
+            color: self.brightness.clamp(0., 1.) * weapon_color,
+            duration: self.duration,
+            blend: self.blend,
+            shape: EffectShape::Fill,
+        }
+    }
+}
+
+impl Default for SwitchFlash {
+    fn default() -> SwitchFlash {
+        SwitchFlash {
+            enabled: false,
+            brightness: 0.4,
+            duration: 0.15,
+            blend: BlendMode::Add,
+        }
+    }
+}
+
+impl Default for KillChase {
+    fn default() -> KillChase {
+        KillChase {
+            enabled: false,
+            color: Color::white(),
+            speed: 150.,
+            lifetime: 0.4,
+            wrap: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+enum StripEnd {
+    Start,
+    End,
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+enum BarAnchor {
+    #[default]
+    Start,
+    End,
+    Center,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct BarAnchors {
+    pub ammo: BarAnchor,
+    pub health: BarAnchor,
+    pub armor: BarAnchor,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct TimeoutDots {
+    pub enabled: bool,
+    pub color: Color,
+    pub position: StripEnd,
+}
+
+impl TimeoutDots {
+    pub fn draw(&self, cols: &mut [Color], count: i32) {
+        let len = cols.len();
+        for i in 0..count.max(0) as usize {
+            let idx = i * 2;
+            if idx >= len {
+                break;
+            }
+            let idx = match self.position {
+                StripEnd::Start => idx,
+                StripEnd::End => len - 1 - idx,
+            };
+            cols[idx] = self.color;
+        }
+    }
+}
+
+impl Default for TimeoutDots {
+    fn default() -> TimeoutDots {
+        TimeoutDots {
+            enabled: false,
+            color: Color::white(),
+            position: StripEnd::Start,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct PhaseThemes {
+    pub warmup: bool,
+    pub warmup_cycle: f64,
+    pub match_end: bool,
+    pub match_end_chase: bool,
+    pub knife_round: bool,
+    pub knife_round_number: i32,
+    pub defusing: bool,
+    pub defuse_heuristic: DefuseHeuristic,
+    pub mvp_duration: f64,
+    pub intermission: bool,
+}
+
+impl Default for PhaseThemes {
+    fn default() -> PhaseThemes {
+        PhaseThemes {
+            warmup: true,
+            warmup_cycle: 8.,
+            match_end: true,
+            match_end_chase: false,
+            knife_round: true,
+            knife_round_number: 0,
+            defusing: true,
+            defuse_heuristic: DefuseHeuristic::default(),
+            mvp_duration: 5.,
+            intermission: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Deserialize)]
+enum OutputRole {
+    #[default]
+    All,
+    Gameplay,
+    Info,
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+struct MatrixLayout {
+    pub width: usize,
+    pub height: usize,
+    #[serde(default)]
+    pub serpentine: bool,
+}
+
+impl MatrixLayout {
+    pub fn map_xy(&self, x: usize, y: usize) -> usize {
+        if self.serpentine && y % 2 == 1 {
+            y * self.width + (self.width - 1 - x)
+        } else {
+            y * self.width + x
+        }
+    }
+
+    pub fn remap(&self, frame: &mut [Color], scratch: &mut Vec<Color>) {
+        if self.width == 0 {
+            return;
+        }
+        scratch.clear();
+        scratch.extend_from_slice(frame);
+        let len = frame.len().min(self.width * self.height);
+        for (i, c) in scratch.iter().take(len).enumerate() {
+            let index = self.map_xy(i % self.width, i / self.width);
+            if index < frame.len() {
+                frame[index] = *c;
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: String,
+    pub stop_bits: u8,
+    pub timeout_ms: u64,
+    pub probe_bauds: Vec<u32>,
+}
+
+impl SerialSettings {
+    pub fn port_settings(&self) -> std::io::Result<serialport::SerialPortSettings> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+        if self.baud_rate == 0 {
+            return Err(invalid("Serial baud rate must be positive".to_string()));
+        }
+        let data_bits = match self.data_bits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            8 => serialport::DataBits::Eight,
+            n => return Err(invalid(format!("Unsupported serial data bits: {}", n))),
+        };
+        let parity = match self.parity.as_str() {
+            "none" => serialport::Parity::None,
+            "odd" => serialport::Parity::Odd,
+            "even" => serialport::Parity::Even,
+            p => return Err(invalid(format!("Unsupported serial parity: {}", p))),
+        };
+        let stop_bits = match self.stop_bits {
+            1 => serialport::StopBits::One,
+            2 => serialport::StopBits::Two,
+            n => return Err(invalid(format!("Unsupported serial stop bits: {}", n))),
+        };
+
+        Ok(serialport::SerialPortSettings {
+            baud_rate: self.baud_rate,
+            data_bits,
+            flow_control: serialport::FlowControl::None,
+            parity,
+            stop_bits,
+            timeout: Duration::from_millis(self.timeout_ms),
+        })
+    }
+}
+
+impl Default for SerialSettings {
+    fn default() -> SerialSettings {
+        SerialSettings {
+            baud_rate: 250000,
+            data_bits: 8,
+            parity: "none".to_string(),
+            stop_bits: 1,
+            timeout_ms: 100,
+            probe_bauds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+struct OutputConfig {
+    pub port: String,
+    pub length: usize,
+    #[serde(default)]
+    pub color_order: ColorOrder,
+    #[serde(default)]
+    pub role: OutputRole,
+    #[serde(default)]
+    pub matrix: Option<MatrixLayout>,
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(untagged)]
+enum Gamma {
+    Uniform(f32),
+    PerChannel(f32, f32, f32),
+}
+
+impl Gamma {
+    pub fn channels(&self) -> (f32, f32, f32) {
+        match *self {
+            Gamma::Uniform(g) => (g, g, g),
+            Gamma::PerChannel(r, g, b) => (r, g, b),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct Twinkle {
+    pub color: Color,
+    pub density: f32,
+    pub decay: f32,
+    pub seed: u64,
+}
+
+impl Default for Twinkle {
+    fn default() -> Twinkle {
+        Twinkle {
+            color: Color(0.3, 0.3, 0.4),
+            density: 0.05,
+            decay: 4.,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(tag = "effect", rename_all = "lowercase")]
+enum IdleEffect {
+    #[default]
+    Off,
+    Solid { color: Color },
+    Breathe { color: Color, period: f64 },
+    Rainbow { period: f64 },
+}
+
+impl IdleEffect {
+    pub fn draw(&self, cols: &mut [Color], time: f64) {
+        match *self {
+            IdleEffect::Off => (),
+            IdleEffect::Solid { color } => fill(cols, color, 1., BlendMode::Mix),
+            IdleEffect::Breathe { color, period } => {
+                let breath = (time / period * std::f64::consts::TAU).cos() * -0.5 + 0.5;
+                fill(cols, color, breath as f32, BlendMode::Mix);
+            },
+            IdleEffect::Rainbow { period } => do_rainbow(cols, time, period, 1.),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NightMode {
+    pub start: String,
+    pub end: String,
+    pub brightness: f32,
+}
+
+impl NightMode {
+    fn parse_time(time: &str) -> Option<u32> {
+        let (hours, minutes) = time.split_once(':')?;
+        let hours: u32 = hours.trim().parse().ok()?;
+        let minutes: u32 = minutes.trim().parse().ok()?;
+        if hours < 24 && minutes < 60 {
+            Some(hours * 60 + minutes)
+        } else {
+            None
+        }
+    }
+
+    pub fn multiplier(&self, minute_of_day: u32) -> f32 {
+        let (start, end) = match (NightMode::parse_time(&self.start), NightMode::parse_time(&self.end)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return 1.,
+        };
+        let inside = if start <= end {
+            minute_of_day >= start && minute_of_day < end
+        } else {
+            minute_of_day >= start || minute_of_day < end
+        };
+        if inside { self.brightness } else { 1. }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WinStreak {
+    pub base: f32,
+    pub step: f32,
+}
+
+impl WinStreak {
+    pub fn intensity(&self, streak: u32) -> f32 {
+        (self.base + self.step * streak.saturating_sub(1) as f32).min(1.)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PowerLimit {
+    pub supply_ma: f32,
+    pub channel_ma: f32,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct Config {
+    pub reverse: bool,
+    pub mirror: bool,
+    pub appids: Vec<i32>,
+    pub diff_output: bool,
+    pub idle_timeout: f64,
+    pub effects: EventEffects,
+    pub kill_chase: KillChase,
+    pub switch_flash: SwitchFlash,
+    pub themes: PhaseThemes,
+    pub freezetime: FreezetimeDisplay,
+    pub freezetime_region: Option<(usize, usize)>,
+    pub loss_bonus_tiers: Vec<Color>,
+    pub rounds_to_win: u32,
+    pub timeouts: TimeoutDots,
+    pub series_pips: bool,
+    pub length: usize,
+    pub self_test: bool,
+    pub weapon_colors: HashMap<String, Color>,
+    pub default_weapon_color: Color,
+    pub health_color: Color,
+    pub armor_color: Color,
+    pub bar_brightness: f32,
+    pub low_ammo_threshold: f32,
+    pub flash_smoothing: f64,
+    pub hot_reload: bool,
+    pub preview: bool,
+    pub fps: f64,
+    pub outputs: Vec<OutputConfig>,
+    pub power_limit: Option<PowerLimit>,
+    pub dithering: bool,
+    pub high_depth: bool,
+    pub transition_time: f64,
+    pub gamma: Gamma,
+    pub max_body_size: usize,
+    pub keepalive_ms: u64,
+    pub spectator_tint: f32,
+    pub spectator_fallback: SpectatorFallback,
+    pub team_tint: f32,
+    pub primary_provider: Option<String>,
+    pub firmware_gamma: bool,
+    pub bar_blend: BlendMode,
+    pub bar_anchors: BarAnchors,
+    pub speed_scale: f64,
+    pub effect_speeds: HashMap<String, f64>,
+    pub chunk_size: usize,
+    pub chunk_delay_ms: u64,
+    pub wait_for_ack: bool,
+    pub serial: SerialSettings,
+    pub win_streak: Option<WinStreak>,
+    pub night_mode: Option<NightMode>,
+    pub idle_twinkle: Option<Twinkle>,
+    pub idle: IdleEffect,
+    pub merge_updates: bool,
+    pub bar_smoothing: f64,
+    #[serde(deserialize_with = "deserialize_reactions")]
+    pub reactions: HashMap<EventType, Reaction>,
+}
+
+impl Config {
+    pub fn reaction(&self, event: EventType) -> Option<Reaction> {
+        if let Some(reaction) = self.reactions.get(&event.key()) {
+            return Some(*reaction);
+        }
+        match event {
+            EventType::KnifeKill => Some(Reaction::Rainbow { duration: 4. }),
+            EventType::TookDamage(amount) => {
+                let mut effect = self.effects.damage;
+                effect.color = (0.25 + amount as f32 / 100.).min(1.) * effect.color;
+                Some(Reaction::Flash(effect))
+            },
+            event => self.effects.get(event).copied().map(Reaction::Flash),
+        }
+    }
+
+    pub fn loss_bonus(&self, losses: i32) -> (usize, usize, Option<Color>) {
+        let tiers = if self.loss_bonus_tiers.is_empty() { LOSS_BONUS_LEVELS } else { self.loss_bonus_tiers.len() };
+        let tier = (losses.max(0) as usize + 1).min(tiers);
+        (tier, tiers, self.loss_bonus_tiers.get(tier - 1).copied())
+    }
+
+    pub fn freezetime_span(&self, len: usize) -> std::ops::Range<usize> {
+        match self.freezetime_region {
+            Some((start, count)) => {
+                let start = start.min(len);
+                start..start.saturating_add(count).min(len)
+            },
+            None => 0..len,
+        }
+    }
+
+    pub fn speed(&self, effect: &str) -> f64 {
+        self.speed_scale * self.effect_speeds.get(effect).copied().unwrap_or(1.)
+    }
+
+    pub fn weapon_color(&self, weapon_type: &str) -> Color {
+        self.weapon_colors.get(weapon_type).copied().unwrap_or(self.default_weapon_color)
+    }
+
+    pub fn output(&self, port: &str) -> Cow<'_, OutputConfig> {
+        match self.outputs.iter().find(|output| output.port == port) {
+            Some(output) => Cow::Borrowed(output),
+            // Looked up every frame, so the fallback leaves the port name empty
+            // instead of allocating a copy of it
+            None => Cow::Owned(OutputConfig {
+                port: String::new(),
+                length: self.length,
+                color_order: ColorOrder::default(),
+                role: OutputRole::default(),
+                matrix: None,
+            }),
+        }
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Config> {
+        match std::fs::File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(std::io::BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            reverse: false,
+            mirror: false,
+            appids: vec![730],
+            diff_output: true,
+            idle_timeout: 30.,
+            effects: EventEffects::default(),
+            kill_chase: KillChase::default(),
+            switch_flash: SwitchFlash::default(),
+            themes: PhaseThemes::default(),
+            freezetime: FreezetimeDisplay::RoundWins,
+            loss_bonus_tiers: Vec::new(),
+            freezetime_region: None,
+            rounds_to_win: 16,
+            timeouts: TimeoutDots::default(),
+            series_pips: false,
+            length: 60,
+            self_test: false,
+            weapon_colors: HashMap::new(),
+            default_weapon_color: Color(0.5, 0., 0.),
+            health_color: palette::HEALTH,
+            armor_color: palette::ARMOR,
+            bar_brightness: 1.,
+            low_ammo_threshold: 0.2,
+            flash_smoothing: 0.1,
+            hot_reload: false,
+            preview: false,
+            fps: 60.,
+            outputs: Vec::new(),
+            power_limit: None,
+            dithering: false,
+            high_depth: false,
+            transition_time: 0.25,
+            gamma: Gamma::Uniform(2.2),
+            max_body_size: 256 * 1024,
+            keepalive_ms: 1000,
+            spectator_tint: 0.15,
+            spectator_fallback: SpectatorFallback::Follow,
+            team_tint: 0.,
+            primary_provider: None,
+            firmware_gamma: false,
+            bar_blend: BlendMode::Add,
+            bar_anchors: BarAnchors::default(),
+            speed_scale: 1.,
+            effect_speeds: HashMap::new(),
+            chunk_size: 0,
+            chunk_delay_ms: 1,
+            wait_for_ack: false,
+            serial: SerialSettings::default(),
+            win_streak: None,
+            night_mode: None,
+            idle_twinkle: None,
+            idle: IdleEffect::Off,
+            merge_updates: false,
+            bar_smoothing: 0.15,
+            reactions: HashMap::new(),
+        }
+    }
+}
+
+async fn watch_config(path: PathBuf, config: Arc<Mutex<Config>>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let m = modified(&path);
+        if m == last_modified {
+            continue;
+        }
+        last_modified = m;
+
+        match Config::load(&path) {
+            Ok(new_config) => {
+                *config.lock().unwrap() = new_config;
+                info!("Reloaded config from {}", path.display());
+            },
+            Err(e) => warn!("Ignoring invalid config {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    AppDirs::new(Some("rust_rgb"), AppUI::CommandLine).map(|dirs| dirs.config_dir.join("config.json"))
+}
+
+struct Args {
+    pub replay: Option<PathBuf>,
+    pub replay_rate: f64,
+    pub record: Option<PathBuf>,
+    pub self_test: bool,
+    pub baud_rate: Option<u32>,
+    pub port: Option<String>,
+    pub output: OutputKind,
+    pub fps_cap: Option<f64>,
+}
+
+impl Args {
+    pub fn parse() -> std::io::Result<Args> {
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+        let mut args = Args {
+            replay: None,
+            replay_rate: 10.,
+            record: None,
+            self_test: false,
+            baud_rate: None,
+            port: None,
+            output: OutputKind::Serial,
+            fps_cap: None,
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--replay" => args.replay = Some(iter.next().ok_or_else(|| invalid("--replay requires a path"))?.into()),
+                "--self-test" => args.self_test = true,
+                "--record" => args.record = Some(iter.next().ok_or_else(|| invalid("--record requires a path"))?.into()),
+                "--port" => args.port = Some(iter.next().ok_or_else(|| invalid("--port requires a port name"))?),
+                "--output" => {
+                    args.output = match iter.next().as_deref() {
+                        Some("serial") => OutputKind::Serial,
+                        Some("null") => OutputKind::Null,
+                        Some("log") => OutputKind::Log,
+                        Some("terminal") => OutputKind::Terminal,
+                        _ => return Err(invalid("--output requires one of serial, null, log or terminal")),
+                    };
+                },
+                "--fps-cap" => {
+                    args.fps_cap = Some(iter.next()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|&v: &f64| v >= 0.)
+                        .ok_or_else(|| invalid("--fps-cap requires a non-negative number"))?);
+                },
+                "--baud" => {
+                    args.baud_rate = Some(iter.next()
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| invalid("--baud requires a number"))?);
+                },
+                "--replay-rate" => {
+                    args.replay_rate = iter.next()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|&v: &f64| v > 0.)
+                        .ok_or_else(|| invalid("--replay-rate requires a positive number"))?;
+                },
+                _ => return Err(invalid(&format!("Unknown argument: {}", arg))),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+const RECENT_EVENTS: usize = 10;
+
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+mod palette {
+    use super::Color;
+
+    pub const CT: Color = Color(0.1, 0.3, 1.0);
+    pub const T: Color = Color(1.0, 0.5, 0.1);
+    pub const BOMB: Color = Color(0.6, 0.4, 0.0);
+    pub const LOW_AMMO: Color = Color(1.0, 0.5, 0.0);
+    pub const HEALTH: Color = Color(0.0, 0.5, 0.0);
+    pub const ARMOR: Color = Color(0.0, 0.0, 0.5);
+    pub const KNIFE: Color = Color(0.2, 0.0, 0.0);
+    pub const C4: Color = Color(0.1, 0.1, 0.0);
+    pub const MONEY: Color = Color(0.1, 0.6, 0.1);
+}
+
+#[derive(Clone, Copy)]
+struct WriteOptions {
+    pub diff: bool,
+    pub high_depth: bool,
+    pub keepalive: Duration,
+    pub chunk_size: usize,
+    pub chunk_delay: Duration,
+    pub wait_for_ack: bool,
+    pub firmware_gamma: bool,
+}
+
+struct ChunkedWriter<W> {
+    inner: W,
+    chunk_size: usize,
+    delay: Duration,
+    written: u64,
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.chunk_size == 0 || buf.len() <= self.chunk_size {
+            let n = self.inner.write(buf)?;
+            self.written += n as u64;
+            return Ok(n);
+        }
+        self.inner.write_all(&buf[..self.chunk_size])?;
+        self.inner.flush()?;
+        self.written += self.chunk_size as u64;
+        std::thread::sleep(self.delay);
+        Ok(self.chunk_size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+const WRITE_RETRIES: usize = 5;
+
+const TERMINAL_MAX_FPS: f64 = 30.;
+
+struct RetryWriter<W> {
+    inner: W,
+    retries: usize,
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.write(buf) {
+                Err(e) if (e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::WouldBlock) && attempt < self.retries => {
+                    attempt += 1;
+                    trace!("Serial write timed out, retrying ({}/{})", attempt, self.retries);
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+trait Output: Send {
+    fn write_frame(&mut self, cols: &[Color], options: WriteOptions) -> std::io::Result<()>;
+    fn clear(&mut self) -> std::io::Result<()>;
+    fn self_test(&mut self, _len: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+    fn bytes_written(&self) -> u64 {
+        0
+    }
+    fn connected(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputKind {
+    Serial,
+    Null,
+    Log,
+    Terminal,
+}
+
+fn open_output(kind: OutputKind, name: &str, settings: &serialport::SerialPortSettings, probe_bauds: &[u32]) -> Box<dyn Output> {
+    match kind {
+        OutputKind::Serial => Box::new(SerialOutput::open(name, settings, probe_bauds)),
+        OutputKind::Null => Box::new(NullOutput),
+        OutputKind::Log => Box::new(LogOutput::new(name)),
+        OutputKind::Terminal => Box::new(TerminalOutput::new()),
+    }
+}
+
+struct NullOutput;
+
+impl Output for NullOutput {
+    fn write_frame(&mut self, _cols: &[Color], _options: WriteOptions) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct LogOutput {
+    name: String,
+    frames: u64,
+    last_log: Instant,
+}
+
+impl LogOutput {
+    pub fn new(name: &str) -> LogOutput {
+        LogOutput {
+            name: name.to_string(),
+            frames: 0,
+            last_log: Instant::now(),
+        }
+    }
+}
+
+impl Output for LogOutput {
+    fn write_frame(&mut self, cols: &[Color], _options: WriteOptions) -> std::io::Result<()> {
+        self.frames += 1;
+        if self.last_log.elapsed() >= Duration::from_secs(1) && !cols.is_empty() {
+            let sum = cols.iter().fold(Color::black(), |a, c| Color(a.0 + c.0, a.1 + c.1, a.2 + c.2));
+            let average = (1. / cols.len() as f32) * sum;
+            info!("{}: {} frames, average {}", self.name, self.frames, average);
+            self.frames = 0;
+            self.last_log = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        info!("{}: cleared", self.name);
+        Ok(())
+    }
+}
+
+struct TerminalOutput {
+    buf: String,
+    last_draw: Option<Instant>,
+}
+
+impl TerminalOutput {
+    pub fn new() -> TerminalOutput {
+        TerminalOutput {
+            buf: String::new(),
+            last_draw: None,
+        }
+    }
+}
+
+impl Output for TerminalOutput {
+    fn write_frame(&mut self, cols: &[Color], _options: WriteOptions) -> std::io::Result<()> {
+        let interval = Duration::from_secs_f64(1. / TERMINAL_MAX_FPS);
+        if self.last_draw.is_some_and(|last| last.elapsed() < interval) {
+            return Ok(());
+        }
+        self.last_draw = Some(Instant::now());
+
+        use std::fmt::Write;
+        self.buf.clear();
+        self.buf.push('\r');
+        for c in cols {
+            let (r, g, b) = c.as_byte_color();
+            let _ = write!(self.buf, "\x1b[48;2;{};{};{}m ", r, g, b);
+        }
+        self.buf.push_str("\x1b[0m");
+
+        let mut stdout = std::io::stdout();
+        stdout.write_all(self.buf.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\r\x1b[0m\x1b[2K")?;
+        stdout.flush()
+    }
+}
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+struct SerialOutput {
+    // Closed after a failed write, since the device is opened exclusively and
+    // can't be reopened while the old handle is alive
+    pub port: Option<Box<dyn serialport::SerialPort>>,
+    name: String,
+    settings: serialport::SerialPortSettings,
+    buf: Vec<u8>,
+    sent: Vec<(u8, u8, u8)>,
+    last_write: Instant,
+    written: u64,
+    connected: bool,
+    reconnect_delay: Duration,
+    next_reconnect: Instant,
+}
+
+impl SerialOutput {
+    pub fn open(name: &str, settings: &serialport::SerialPortSettings, probe_bauds: &[u32]) -> SerialOutput {
+        let mut settings = *settings;
+        let mut port = open_serial(name, &settings);
+        if !probe_bauds.is_empty() {
+            match negotiate_baud(&mut *port, probe_bauds) {
+                Some(baud_rate) => {
+                    info!("Negotiated {} baud on {}", baud_rate, name);
+                    settings.baud_rate = baud_rate;
+                },
+                None => {
+                    warn!("No baud rate acknowledged on {}, falling back to {}", name, settings.baud_rate);
+                    if let Err(e) = port.set_baud_rate(settings.baud_rate) {
+                        warn!("Failed to restore baud rate on {}: {}", name, e);
+                    }
+                },
+            }
+        }
+        SerialOutput {
+            port: Some(port),
+            name: name.to_owned(),
+            settings,
+            buf: Vec::new(),
+            sent: Vec::new(),
+            last_write: Instant::now(),
+            written: 0,
+            connected: true,
+            reconnect_delay: RECONNECT_MIN_DELAY,
+            next_reconnect: Instant::now(),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        // The controller may hold a partial instruction, so forget what it shows
+        // and send a full frame once the port is back
+        self.sent.clear();
+        self.port = None;
+        self.next_reconnect = Instant::now() + self.reconnect_delay;
+    }
+
+    fn reconnect(&mut self) {
+        if self.port.is_some() || Instant::now() < self.next_reconnect {
+            return;
+        }
+        match serialport::open_with_settings(&self.name, &self.settings) {
+            Ok(port) => {
+                info!("Reconnected to {}", self.name);
+                self.port = Some(port);
+                self.reconnect_delay = RECONNECT_MIN_DELAY;
+            },
+            Err(e) => {
+                warn!("Failed to reconnect to {}: {}", self.name, e);
+                self.reconnect_delay = (self.reconnect_delay * 2).min(RECONNECT_MAX_DELAY);
+                self.next_reconnect = Instant::now() + self.reconnect_delay;
+            },
+        }
+    }
+
+    fn send(&mut self, cols: &[Color], options: WriteOptions) -> std::io::Result<()> {
+        let port = match &mut self.port {
+            Some(port) => port,
+            None => return Ok(()),
+        };
+        let mut w = ChunkedWriter {
+            inner: RetryWriter {
+                inner: &mut *port,
+                retries: WRITE_RETRIES,
+            },
+            chunk_size: options.chunk_size,
+            delay: options.chunk_delay,
+            written: 0,
+        };
+        let force = self.last_write.elapsed() >= options.keepalive;
+        let result = send_frame(&mut w, &mut self.buf, cols, &mut self.sent, options, force);
+        self.written += w.written;
+        let wrote = result?;
+
+        if wrote {
+            self.last_write = Instant::now();
+            if options.wait_for_ack {
+                let mut ack = [0];
+                match port.read(&mut ack) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => warn!("Timed out waiting for frame acknowledgement"),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for SerialOutput {
+    fn write_frame(&mut self, cols: &[Color], options: WriteOptions) -> std::io::Result<()> {
+        self.reconnect();
+        let result = self.send(cols, options);
+        self.connected = result.is_ok() && self.port.is_some();
+        if let Err(e) = &result {
+            warn!("Serial write to {} failed: {}", self.name, e);
+            self.disconnect();
+        }
+        result
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        self.sent.clear();
+        if let Some(port) = &mut self.port {
+            Instruction::Clear.write(port, &mut self.buf)?;
+            Instruction::Show.write(port, &mut self.buf)?;
+            port.flush()?;
+        }
+        Ok(())
+    }
+
+    fn self_test(&mut self, len: usize) -> std::io::Result<()> {
+        match &mut self.port {
+            Some(port) => self_test(port, len),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "Serial port is disconnected")),
+        }
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    fn connected(&self) -> bool {
+        self.connected
+    }
+}
+
+const PING_ACK: u8 = 0x06;
+
+// The part of a serial port that baud negotiation needs
+trait BaudProbe: Read + Write {
+    fn set_baud(&mut self, baud_rate: u32) -> serialport::Result<()>;
+    fn clear_input(&mut self) -> serialport::Result<()>;
+}
+
+impl BaudProbe for dyn serialport::SerialPort {
+    fn set_baud(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.set_baud_rate(baud_rate)
+    }
+
+    fn clear_input(&mut self) -> serialport::Result<()> {
+        self.clear(serialport::ClearBuffer::Input)
+    }
+}
+
+// Firmware that understands Ping answers with a single PING_ACK byte, which only
+// arrives intact when both ends agree on the baud rate
+fn negotiate_baud<P: BaudProbe + ?Sized>(mut port: &mut P, bauds: &[u32]) -> Option<u32> {
+    let mut buf = Vec::new();
+    for &baud_rate in bauds {
+        if let Err(e) = port.set_baud(baud_rate) {
+            warn!("Failed to set baud rate {}: {}", baud_rate, e);
+            continue;
+        }
+        let _ = port.clear_input();
+        if let Err(e) = Instruction::Ping.write(&mut port, &mut buf) {
+            warn!("Failed to ping at {} baud: {}", baud_rate, e);
+            continue;
+        }
+        let mut ack = [0];
+        match port.read(&mut ack) {
+            Ok(1) if ack[0] == PING_ACK => return Some(baud_rate),
+            _ => trace!("No acknowledgement at {} baud", baud_rate),
+        }
+    }
+    None
+}
+
+fn open_serial(name: &str, settings: &serialport::SerialPortSettings) -> Box<dyn serialport::SerialPort> {
+    serialport::open_with_settings(name, settings).expect("Failed to open serial port")
+}
+
+fn self_test(s: &mut dyn Write, len: usize) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(2 + len * 3);
+    let mut cols = vec![Color::black(); len];
+
+    info!("Self-test: first pixel");
+    draw_line(&mut cols, 0., 1., Color::red(), BlendMode::Replace);
+    Instruction::SetPixels(&cols).write(s, &mut buf)?;
+    Instruction::Show.write(s, &mut buf)?;
+    std::thread::sleep(Duration::from_secs(1));
+
+    info!("Self-test: walking pixel");
+    for i in 0..len {
+        clear(&mut cols);
+        draw_line(&mut cols, i as f32, i as f32 + 1., Color::white(), BlendMode::Replace);
+        Instruction::SetPixels(&cols).write(s, &mut buf)?;
+        Instruction::Show.write(s, &mut buf)?;
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    info!("Self-test: color thirds");
+    let third = len as f32 / 3.;
+    clear(&mut cols);
+    draw_line(&mut cols, 0., third, Color::red(), BlendMode::Replace);
+    draw_line(&mut cols, third, 2. * third, Color::green(), BlendMode::Replace);
+    draw_line(&mut cols, 2. * third, len as f32, Color::blue(), BlendMode::Replace);
+    Instruction::SetPixels(&cols).write(s, &mut buf)?;
+    Instruction::Show.write(s, &mut buf)?;
+    std::thread::sleep(Duration::from_secs(2));
+
+    Instruction::Clear.write(s, &mut buf)?;
+    Instruction::Show.write(s, &mut buf)?;
+    s.flush()
+}
+
+struct Strip {
+    pub name: String,
+    pub output: Box<dyn Output>,
+    pub frame: Vec<Color>,
+    pub dither: Vec<(f32, f32, f32)>,
+    pub display: DisplayState,
+    pub base: Vec<Color>,
+    pub fade_from: Vec<Color>,
+    pub fade_start: f64,
+    pub scratch: Vec<Color>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Team {
+    CT,
+    T,
+}
+
+impl Team {
+    pub fn parse(name: &str) -> Option<Team> {
+        match name {
+            "CT" => Some(Team::CT),
+            "T" => Some(Team::T),
+            _ => None,
+        }
+    }
+
+    pub fn color(self) -> Color {
+        match self {
+            Team::CT => palette::CT,
+            Team::T => palette::T,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DisplayState {
+    Idle,
+    Warmup,
+    MatchEnd(Option<Team>),
+    Intermission,
+    Freezetime,
+    Mvp,
+    RoundOver(Team),
+    KnifeRound,
+    BombDefusing,
+    Live,
+}
+
+impl DisplayState {
+    pub fn from_state(state: &GameState, mvp: bool, themes: &PhaseThemes) -> DisplayState {
+        let map = match &state.map {
+            Some(map) => map,
+            None => return DisplayState::Idle,
+        };
+        if map.phase == "warmup" && themes.warmup {
+            return DisplayState::Warmup;
+        }
+        if map.phase == "intermission" && themes.intermission {
+            // Sides swap at halftime, so don't show either team's color
+            return DisplayState::Intermission;
+        }
+        if (map.phase == "gameover" || map.phase == "intermission") && themes.match_end {
+            let winner = if map.team_ct.score > map.team_t.score {
+                Some(Team::CT)
+            } else if map.team_t.score > map.team_ct.score {
+                Some(Team::T)
+            } else {
+                None
+            };
+            return DisplayState::MatchEnd(winner);
+        }
+        let round = match &state.round {
+            Some(round) => round,
+            None => return DisplayState::Idle,
+        };
+        if round.phase == "freezetime" {
+            DisplayState::Freezetime
+        } else if mvp {
+            DisplayState::Mvp
+        } else if let Some(team) = round.win_team.as_deref().and_then(Team::parse) {
+            DisplayState::RoundOver(team)
+        } else if themes.defusing && (state.bomb.as_ref().is_some_and(|bomb| bomb.state == "defusing") || themes.defuse_heuristic.defusing(state)) {
+            DisplayState::BombDefusing
+        } else if themes.knife_round && round.phase == "live" && map.round == themes.knife_round_number && state.only_knife() {
+            DisplayState::KnifeRound
+        } else {
+            DisplayState::Live
+        }
+    }
+
+    pub fn for_role(self, role: OutputRole) -> DisplayState {
+        match (role, self) {
+            (OutputRole::All, state) => state,
+            (OutputRole::Gameplay, DisplayState::Idle) => DisplayState::Idle,
+            (OutputRole::Gameplay, _) => DisplayState::Live,
+            (OutputRole::Info, DisplayState::Live) => DisplayState::Idle,
+            (OutputRole::Info, state) => state,
+        }
+    }
+}
+
+struct RenderCtx<'a> {
+    pub config: &'a Config,
+    pub now: Instant,
+    pub time: f64,
+    pub knife_time: f64,
+    pub planted_time: Option<f64>,
+    pub display: DisplayState,
+    pub info: bool,
+    pub gameplay: bool,
+    pub team_color: Option<Color>,
+    pub round_over: Option<(Team, f64)>,
+    pub defuse_progress: f32,
+    pub health: f32,
+    pub armor: f32,
+    pub ammo: f32,
+    pub flashed: f32,
+    pub reactions: &'a [ActiveReaction],
+}
+
+impl RenderCtx<'_> {
+    pub fn anim_time(&self, effect: &str) -> f64 {
+        self.time * self.config.speed(effect)
+    }
+
+    pub fn tint(&self, c: Color) -> Color {
+        match self.team_color {
+            Some(team) => c.mix(team, self.config.team_tint.clamp(0., 1.)),
+            None => c,
+        }
+    }
+}
+
+fn render_frame(state: &GameState, ctx: &RenderCtx, cols: &mut [Color]) {
+    let len = cols.len();
+    clear(cols);
+
+    if let Some(map) = &state.map {
+        trace!("Map: {}", map.phase);
+        match ctx.display {
+            DisplayState::Idle => (),
+            DisplayState::Warmup => {
+                let cycle = ctx.config.themes.warmup_cycle;
+                let time = ctx.anim_time("warmup");
+                let breath = (time / cycle * std::f64::consts::TAU).cos() * -0.25 + 0.5;
+                do_rainbow(cols, time, cycle, breath as f32);
+            },
+            DisplayState::MatchEnd(winner) => {
+                let color = winner.map_or(Color::white(), Team::color);
+                if ctx.config.themes.match_end_chase {
+                    do_chase(cols, ctx.anim_time("match_end"), 10., 6, color);
+                } else {
+                    fill(cols, color, 1., BlendMode::Mix);
+                }
+            },
+            DisplayState::Intermission => {
+                let breath = (ctx.anim_time("intermission") / 4. * std::f64::consts::TAU).cos() * -0.15 + 0.25;
+                fill(cols, Color::white(), breath as f32, BlendMode::Mix);
+            },
+            DisplayState::Freezetime => {
+                if ctx.config.freezetime_region.is_some() && ctx.gameplay {
+                    draw_hud(state, ctx, cols);
+                }
+                // The region is reserved for the readout, so the HUD must not show through
+                let cols = &mut cols[ctx.config.freezetime_span(len)];
+                clear(cols);
+                let len = cols.len();
+                let team = state.player.as_ref().and_then(|player| player.team.as_deref()).and_then(Team::parse);
+                match ctx.config.freezetime {
+                    FreezetimeDisplay::RoundWins => {
+                        let seg = len as f32 / map.round_wins.len() as f32;
+                        for (i, win) in map.round_wins.iter().enumerate() {
+                            draw_line(cols, i as f32 * seg, (i + 1) as f32 * seg, win.kind.color(win.team), BlendMode::Replace);
+                        }
+                    },
+                    FreezetimeDisplay::Scores => draw_score(cols, map.team_ct.score, map.team_t.score, ctx.config.rounds_to_win.max(1)),
+                    FreezetimeDisplay::Money => if let Some(player) = state.player.as_ref().and_then(|player| player.state.as_ref()) {
+                        let amount = (player.money.max(0) as f32 / MAX_MONEY).min(1.);
+                        draw_line(cols, 0., len as f32 * amount, palette::MONEY, BlendMode::Replace);
+                    },
+                    FreezetimeDisplay::LossBonus => if let Some(team) = team {
+                        let info = match team {
+                            Team::CT => &map.team_ct,
+                            Team::T => &map.team_t,
+                        };
+                        let (tier, tiers, color) = ctx.config.loss_bonus(info.consecutive_round_losses);
+                        draw_line(cols, 0., len as f32 * tier as f32 / tiers as f32, color.unwrap_or_else(|| team.color()), BlendMode::Replace);
+                    },
+                }
+
+                if let Some(BombCarrier::Carrying) | Some(BombCarrier::Teammate) = state.bomb_carrier() {
+                    draw_line(cols, len as f32 - 1., len as f32, palette::BOMB, BlendMode::Replace);
+                }
+            },
+            DisplayState::Mvp => do_rainbow(cols, ctx.anim_time("mvp"), 1., 1.),
+            DisplayState::RoundOver(team) => {
+                let intensity = match &ctx.config.win_streak {
+                    Some(streak) if state.player.as_ref().and_then(|p| p.team.as_deref()).and_then(Team::parse) == Some(team) => {
+                        streak.intensity(state.win_streak())
+                    },
+                    _ => 1.,
+                };
+                let since = ctx.round_over.map_or(WIN_WIPE_TIME, |(_, start)| (ctx.time - start) * ctx.config.speed("round_over"));
+                let front = (since / WIN_WIPE_TIME).min(1.) as f32 * len as f32;
+                match team {
+                    Team::CT => draw_line(cols, 0., front, intensity * team.color(), BlendMode::Replace),
+                    Team::T => draw_line(cols, len as f32 - front, len as f32, intensity * team.color(), BlendMode::Replace),
+                }
+            },
+            DisplayState::BombDefusing => {
+                let progress = ctx.defuse_progress;
+                draw_line(cols, 0., len as f32 * progress, palette::CT, BlendMode::Replace);
+                draw_line(cols, len as f32 * progress, len as f32, palette::BOMB, BlendMode::Replace);
+            },
+            DisplayState::KnifeRound => {
+                let mid = len as f32 * (0.5 + 0.15 * (ctx.anim_time("knife_round") * 3.).sin() as f32);
+                draw_line(cols, 0., mid, palette::CT, BlendMode::Replace);
+                draw_line(cols, mid, len as f32, palette::T, BlendMode::Replace);
+            },
+            DisplayState::Live => draw_hud(state, ctx, cols),
+        }
+
+        let in_timeout = map.phase.starts_with("timeout");
+        let in_freezetime = state.round.as_ref().is_some_and(|round| round.phase == "freezetime");
+        let cols = if in_freezetime { &mut cols[ctx.config.freezetime_span(len)] } else { cols };
+        if ctx.info && ctx.config.series_pips && in_freezetime {
+            draw_series(cols, map, ctx.config.rounds_to_win.max(1), ctx.anim_time("series"));
+        }
+        if ctx.info && ctx.config.timeouts.enabled && (in_timeout || in_freezetime) {
+            let team = match state.player.as_ref().and_then(|player| player.team.as_deref()) {
+                Some("CT") => Some(&map.team_ct),
+                Some("T") => Some(&map.team_t),
+                _ => None,
+            };
+            if let Some(team) = team {
+                ctx.config.timeouts.draw(cols, team.timeouts_remaining);
+            }
+        }
+    } else {
+        ctx.config.idle.draw(cols, ctx.anim_time("idle"));
+    }
+}
+
+fn draw_hud(state: &GameState, ctx: &RenderCtx, cols: &mut [Color]) {
+    let has_state = state.player.as_ref().is_some_and(|player| player.state.is_some());
+    if state.is_spectating() || !has_state {
+        match ctx.config.spectator_fallback {
+            SpectatorFallback::Follow => (),
+            SpectatorFallback::Scoreboard => {
+                if let Some(map) = &state.map {
+                    draw_score(cols, map.team_ct.score, map.team_t.score, ctx.config.rounds_to_win.max(1));
+                }
+                return;
+            },
+            SpectatorFallback::Idle => {
+                ctx.config.idle.draw(cols, ctx.anim_time("idle"));
+                return;
+            },
+        }
+    }
+
+    if let (Some(player), Some((_k, w))) = (&state.player, state.active_weapon()) {
+        if state.is_spectating() {
+            if let Some(team) = player.team.as_deref().and_then(Team::parse) {
+                fill(cols, team.color(), ctx.config.spectator_tint, BlendMode::Mix);
+            }
+        }
+        if w.category() == WeaponCategory::Knife {
+            let knife_time = ctx.knife_time * ctx.config.speed("knife");
+            let cycle = (knife_time % 1.321 + 1.321) % 1.321;
+            let amt = if cycle < 0.25 {
+                0.5 - cycle * 2.
+            } else if cycle < 0.5 {
+                0.5 - cycle
+            } else {
+                0.
+            };
+
+            fill(cols, palette::KNIFE, amt as f32, BlendMode::Mix);
+        } else if state.bomb_carrier() == Some(BombCarrier::Holding) {
+            let breath = (ctx.anim_time("c4") * std::f64::consts::TAU).cos() * -0.25 + 0.5;
+            fill(cols, palette::C4, breath as f32, BlendMode::Mix);
+        } else if player.state.is_some() {
+            let clip = match (w.ammo_clip, w.ammo_clip_max) {
+                (Some(clip), Some(max)) if max > 0 => Some((clip as f64 / max as f64) as f32),
+                _ => None,
+            };
+            let ammo = if clip.is_some() { ctx.ammo } else { 0. };
+
+            let len = cols.len();
+            let brightness = ctx.config.bar_brightness.clamp(0., 1.);
+            let anchors = &ctx.config.bar_anchors;
+            draw_bar(cols, ammo, anchors.ammo, brightness * ctx.tint(ctx.config.weapon_color(&w.r#type)), ctx.config.bar_blend);
+            if clip.is_some_and(|ammo| ammo < ctx.config.low_ammo_threshold) && (ctx.anim_time("low_ammo") * 4.).fract() < 0.5 {
+                draw_bar(cols, f32::max(ammo, 1. / len as f32), anchors.ammo, palette::LOW_AMMO, BlendMode::Mix);
+            }
+            draw_bar(cols, ctx.health, anchors.health, brightness * ctx.tint(ctx.config.health_color), ctx.config.bar_blend);
+            draw_bar(cols, ctx.armor, anchors.armor, brightness * ctx.tint(ctx.config.armor_color), ctx.config.bar_blend);
+        }
+    }
+
+    if let Some(since) = ctx.planted_time {
+        // Beep faster as the fuse runs out, like the in-game bomb
+        let remaining = state.bomb.as_ref().and_then(BombInfo::countdown).map_or(BOMB_FUSE - since, f64::from);
+        let period = (remaining / BOMB_FUSE).clamp(0.1, 1.);
+        if (since * ctx.config.speed("bomb_beep")) % period < 0.08 {
+            let len = cols.len() as f32;
+            draw_line(cols, len - 2., len, palette::BOMB, BlendMode::Replace);
+        }
+    }
+}
+
+fn render_overlays(state: &GameState, ctx: &RenderCtx, cols: &mut [Color]) {
+    if ctx.gameplay {
+        for r in ctx.reactions {
+            r.reaction.draw(cols, r.elapsed(ctx.time, ctx.config), ctx.anim_time("rainbow"), &ctx.config.kill_chase, r.reverse);
+        }
+
+        if ctx.flashed > 0. {
+            fill(cols, ctx.config.effects.flashbang, ctx.flashed / 255., ctx.config.effects.flashbang_blend);
+        }
+    }
+
+    let mut idle = ctx.display == DisplayState::Idle;
+    if let Some(received) = state.received {
+        let stale = ctx.now.saturating_duration_since(received).as_secs_f64() - ctx.config.idle_timeout;
+        if ctx.config.idle_timeout > 0. && stale > 0. {
+            fill(cols, Color::black(), stale.min(1.) as f32, BlendMode::Mix);
+            idle = true;
+        }
+    }
+    if idle {
+        if let Some(t) = &ctx.config.idle_twinkle {
+            twinkle(cols, ctx.anim_time("twinkle"), t.seed, t.color, t.density, t.decay);
+        }
+    }
+}
+
+// Event-driven effect state that outlives a single frame
+#[derive(Default)]
+struct EffectState {
+    pub mvp: Option<f64>,
+    pub bomb_planted: Option<f64>,
+    pub reactions: Vec<ActiveReaction>,
+    pub chase_reverse: bool,
+    pub last_shot: Option<f64>,
+    pub phase_over: bool,
+}
+
+impl EffectState {
+    pub fn handle_event(&mut self, e: EventType, time: f64, config: &Config, state: &GameState) {
+        if e == EventType::Shoot {
+            // Sprays fire many shots in quick succession, keep them from retriggering constantly
+            if self.last_shot.is_some_and(|last| time - last < config.effects.shoot_cooldown) {
+                return;
+            }
+            self.last_shot = Some(time);
+        }
+        info!("Event: {:?}", e);
+        match e {
+            EventType::MVP => self.mvp = Some(time),
+            EventType::NewRound | EventType::Death => self.mvp = None,
+            EventType::BombPlanted => self.bomb_planted = Some(time),
+            _ => (),
+        }
+        if let EventType::NewRound | EventType::BombDefused | EventType::BombExploded = e {
+            self.bomb_planted = None;
+        }
+        if let Some(reaction) = config.reaction(e) {
+            if let Reaction::Chase = reaction {
+                self.chase_reverse = !self.chase_reverse;
+            } else if e == EventType::Kill {
+                // Kills stack so that quick multi-kills show overlapping pulses
+                let pulses = self.reactions.iter().filter(|r| r.event == Some(EventType::Kill)).count();
+                if pulses >= MAX_KILL_PULSES {
+                    if let Some(oldest) = self.reactions.iter().position(|r| r.event == Some(EventType::Kill)) {
+                        self.reactions.remove(oldest);
+                    }
+                }
+            } else {
+                self.reactions.retain(|r| r.event == Some(EventType::Kill) || std::mem::discriminant(&r.reaction) != std::mem::discriminant(&reaction));
+            }
+            self.reactions.push(ActiveReaction { event: Some(e), reaction, start: time, reverse: self.chase_reverse });
+        }
+        if e == EventType::SwitchWeapon && config.switch_flash.enabled {
+            if let Some((_, w)) = state.active_weapon() {
+                self.reactions.retain(|r| r.event != Some(EventType::SwitchWeapon));
+                let reaction = Reaction::Flash(config.switch_flash.effect(config.weapon_color(&w.r#type)));
+                self.reactions.push(ActiveReaction { event: Some(e), reaction, start: time, reverse: self.chase_reverse });
+            }
+        }
+        if let EventType::Kill | EventType::KnifeKill = e {
+            if config.kill_chase.enabled {
+                self.chase_reverse = !self.chase_reverse;
+                self.reactions.push(ActiveReaction { event: None, reaction: Reaction::Chase, start: time, reverse: self.chase_reverse });
+            }
+        }
+    }
+
+    pub fn update(&mut self, state: &GameState, time: f64, config: &Config) {
+        // Drop lingering flashes and pulses so they don't bleed into the round-over fill
+        let over = state.round.as_ref().is_some_and(|round| round.phase == "over");
+        if over && !self.phase_over {
+            self.reactions.clear();
+        }
+        self.phase_over = over;
+
+        if state.map.is_none() || self.mvp.is_some_and(|start| time - start > config.themes.mvp_duration) {
+            self.mvp = None;
+        }
+        if state.map.is_none() || self.bomb_planted.is_some_and(|start| time - start > BOMB_FUSE) {
+            self.bomb_planted = None;
+        }
+    }
+
+    pub fn expire(&mut self, time: f64, config: &Config) {
+        self.reactions.retain(|r| r.elapsed(time, config) <= r.reaction.duration(&config.kill_chase));
+    }
+}
+
+async fn do_lights(outputs: Vec<(String, Box<dyn Output>)>, config: Arc<Mutex<Config>>, state: watch::Receiver<Arc<GameState>>, mut next_event: mpsc::UnboundedReceiver<EventType>, shutdown: Arc<AtomicBool>, status: Arc<LightsStatus>, preview: Option<watch::Sender<Vec<u8>>>) {
+    let start = Instant::now();
+    let mut knife_start = Instant::now();
+    let mut held: Option<WeaponCategory> = None;
+    let mut effects = EffectState::default();
+
+    let (mut strips, run_self_test, fps) = {
+        let config = config.lock().unwrap();
+        let strips: Vec<Strip> = outputs.into_iter().map(|(name, output)| Strip {
+            frame: vec![Color::blue(); config.output(&name).length],
+            name,
+            output,
+            dither: Vec::new(),
+            display: DisplayState::Idle,
+            base: Vec::new(),
+            fade_from: Vec::new(),
+            fade_start: 0.,
+            scratch: Vec::new(),
+        }).collect();
+        (strips, config.self_test, config.fps)
+    };
+
+    if run_self_test {
+        strips = tokio::task::spawn_blocking(move || {
+            for strip in &mut strips {
+                if let Err(e) = strip.output.self_test(strip.frame.len()) {
+                    error!("Self-test on {} failed: {}", strip.name, e);
+                }
+            }
+            strips
+        }).await.expect("Self-test panicked");
+    }
+
+    let mut defuse_total: f32 = 0.;
+    let mut defuse_start: Option<f64> = None;
+    let mut round_over: Option<(Team, f64)> = None;
+    let mut preview_buf: Vec<u8> = Vec::new();
+    let mut preview_sent: Vec<u8> = Vec::new();
+    let mut health = 0.;
+    let mut armor = 0.;
+    let mut ammo = 0.;
+    let mut flashed = 0.;
+    let mut last_frame = Instant::now();
+    let mut interval = if fps > 0. {
+        Some(tokio::time::interval(Duration::from_secs_f64(1. / fps)))
+    } else {
+        None
+    };
+    let mut powered = true;
+    let mut stats_start = Instant::now();
+    let mut stats_frames = 0u32;
+    let mut stats_compute = Duration::from_secs(0);
+    let mut stats_bytes = 0;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match &mut interval {
+            Some(interval) => { interval.tick().await; },
+            None => tokio::task::yield_now().await,
+        }
+
+        if !status.enabled.load(Ordering::SeqCst) {
+            while next_event.try_recv().is_ok() {}
+            if powered {
+                powered = false;
+                strips = tokio::task::spawn_blocking(move || {
+                    for strip in &mut strips {
+                        if let Err(e) = strip.output.clear() {
+                            error!("Failed to clear {}: {}", strip.name, e);
+                        }
+                    }
+                    strips
+                }).await.expect("Serial writer panicked");
+            }
+            continue;
+        }
+        powered = true;
 
-    loop {
         let now = Instant::now();
         let time_now = (now - start).as_secs_f64();
-        {
-            for e in next_event.lock().unwrap().drain(..) {
-                println!("{:?}", e);
-                match e {
-                    EventType::SwitchWeapon => knife_start = now,
-                    EventType::MVP => mvp = true,
-                    EventType::NewRound => mvp = false,
-                    e @ EventType::Shoot | e @ EventType::Death | e @ EventType::Kill => last_event = Some((e, time_now)),
-                    e @ EventType::KnifeKill => kill_event = Some((e, time_now)),
-                    _ => (),
+        let dt = (now - last_frame).as_secs_f64();
+        last_frame = now;
+
+        let options = {
+            let config = config.lock().unwrap();
+            let latest = Arc::clone(&state.borrow());
+            let state: &GameState = &latest;
+
+            // Before draining, so reactions to events arriving with the round end survive its cleanup
+            effects.update(state, time_now, &config);
+            let mut events = Vec::new();
+            while let Ok(e) = next_event.try_recv() {
+                push_event(&mut events, e);
+            }
+            for e in events {
+                effects.handle_event(e, time_now, &config, state);
+            }
+            // Only drawing the knife restarts its shimmer, so switching through other
+            // weapons doesn't disturb the phase of their own effects
+            let category = state.active_weapon().map(|(_, w)| w.category());
+            if category != held {
+                if category == Some(WeaponCategory::Knife) {
+                    knife_start = now;
+                }
+                held = category;
+            }
+            let display = DisplayState::from_state(state, effects.mvp.is_some(), &config.themes);
+            let team_color = state.team().map(Team::color);
+            match display {
+                DisplayState::RoundOver(team) if round_over.is_some_and(|(t, _)| t == team) => (),
+                DisplayState::RoundOver(team) => round_over = Some((team, time_now)),
+                _ => round_over = None,
+            }
+            let defuse_progress = if display == DisplayState::BombDefusing {
+                let start = *defuse_start.get_or_insert(time_now);
+                match state.bomb.as_ref().and_then(BombInfo::countdown) {
+                    Some(remaining) => {
+                        defuse_total = defuse_total.max(remaining);
+                        1. - remaining / defuse_total.max(0.001)
+                    },
+                    // Without a countdown, fill over the usual defuse time
+                    None => {
+                        let kit = state.player.as_ref()
+                            .and_then(|player| player.state.as_ref())
+                            .and_then(|s| s.defusekit)
+                            .unwrap_or(false);
+                        let duration = if kit { DEFUSE_TIME_KIT } else { DEFUSE_TIME };
+                        ((time_now - start) / duration).min(1.) as f32
+                    },
+                }
+            } else {
+                defuse_total = 0.;
+                defuse_start = None;
+                0.
+            };
+
+            let target = state.player.as_ref()
+                .and_then(|player| player.state.as_ref())
+                .map_or(0., PlayerState::flash_amount);
+            flashed = ease(flashed, target, dt, config.flash_smoothing);
+
+            if let Some(player) = state.player.as_ref().and_then(|player| player.state.as_ref()) {
+                health = ease(health, player.health_fraction(), dt, config.bar_smoothing);
+                armor = ease(armor, player.armor_fraction(), dt, config.bar_smoothing);
+            }
+            if let Some((_, w)) = state.active_weapon() {
+                let target = match (w.ammo_clip, w.ammo_clip_max) {
+                    (Some(clip), Some(max)) if max > 0 => (clip as f32 / max as f32).clamp(0., 1.),
+                    _ => 0.,
+                };
+                ammo = if target > ammo { target } else { ease(ammo, target, dt, config.bar_smoothing) };
+            }
+
+            let mut ctx = RenderCtx {
+                config: &config,
+                now,
+                time: time_now,
+                knife_time: (now - knife_start).as_secs_f64(),
+                planted_time: effects.bomb_planted.map(|time| time_now - time),
+                display,
+                info: true,
+                gameplay: true,
+                team_color,
+                round_over,
+                defuse_progress,
+                health,
+                armor,
+                ammo,
+                flashed,
+                reactions: &effects.reactions,
+            };
+
+            for (i, strip) in strips.iter_mut().enumerate() {
+                let spec = config.output(&strip.name);
+                ctx.info = spec.role != OutputRole::Gameplay;
+                ctx.gameplay = spec.role != OutputRole::Info;
+                ctx.display = display.for_role(spec.role);
+                let display = ctx.display;
+
+                let frame = &mut strip.frame;
+                frame.resize(spec.length, Color::black());
+
+                let len = if config.mirror { frame.len().div_ceil(2) } else { frame.len() };
+                let cols = &mut frame[..len];
+
+                render_frame(state, &ctx, cols);
+
+                if display != strip.display {
+                    strip.display = display;
+                    strip.fade_from.clone_from(&strip.base);
+                    strip.fade_start = time_now;
+                }
+                if config.transition_time > 0. && strip.fade_from.len() == cols.len() {
+                    let t = (time_now - strip.fade_start) / config.transition_time;
+                    if t < 1. {
+                        crossfade(cols, &strip.fade_from, t as f32);
+                    }
+                }
+                strip.base.clear();
+                strip.base.extend_from_slice(cols);
+
+                render_overlays(state, &ctx, cols);
+
+                orient(frame, config.mirror, config.reverse);
+                if let Some(night) = &config.night_mode {
+                    let local = chrono::Local::now();
+                    let multiplier = night.multiplier(local.hour() * 60 + local.minute());
+                    if multiplier < 1. {
+                        for c in frame.iter_mut() {
+                            *c = multiplier * *c;
+                        }
+                    }
+                }
+                if i == 0 {
+                    if let Some(preview) = &preview {
+                        preview_buf.clear();
+                        for c in frame.iter() {
+                            let (r, g, b) = c.as_byte_color();
+                            preview_buf.extend_from_slice(&[r, g, b]);
+                        }
+                        // The channel takes ownership, so only hand over a copy when the frame changed
+                        if preview_buf != preview_sent {
+                            preview_sent.clone_from(&preview_buf);
+                            let _ = preview.broadcast(preview_buf.clone());
+                        }
+                    }
+                }
+
+                if !config.firmware_gamma || config.high_depth {
+                    let gamma = config.gamma.channels();
+                    for c in frame.iter_mut() {
+                        *c = c.with_gamma(gamma);
+                    }
+                }
+                if config.dithering {
+                    strip.dither.resize(frame.len(), (0., 0., 0.));
+                    for (c, error) in frame.iter_mut().zip(strip.dither.iter_mut()) {
+                        *c = c.dither(error);
+                    }
+                }
+                // Current draw follows the encoded values, so limit after gamma and dithering
+                if let Some(limit) = &config.power_limit {
+                    limit_power(frame, limit.supply_ma / limit.channel_ma);
+                }
+
+                if let Some(matrix) = &spec.matrix {
+                    matrix.remap(frame, &mut strip.scratch);
+                }
+
+                for c in frame.iter_mut() {
+                    *c = spec.color_order.reorder(*c);
+                }
+            }
+
+            effects.expire(time_now, &config);
+
+            WriteOptions {
+                diff: config.diff_output,
+                high_depth: config.high_depth,
+                keepalive: Duration::from_millis(config.keepalive_ms),
+                chunk_size: config.chunk_size,
+                chunk_delay: Duration::from_millis(config.chunk_delay_ms),
+                wait_for_ack: config.wait_for_ack,
+                firmware_gamma: config.firmware_gamma && !config.high_depth,
+            }
+        };
+        stats_compute += now.elapsed();
+
+        let s = tokio::task::spawn_blocking(move || {
+            for strip in &mut strips {
+                if let Err(e) = strip.output.write_frame(&strip.frame, options) {
+                    error!("Failed to write frame to {}: {}", strip.name, e);
+                }
+            }
+            strips
+        }).await.expect("Serial writer panicked");
+        strips = s;
+        status.connected.store(strips.iter().all(|strip| strip.output.connected()), Ordering::SeqCst);
+
+        stats_frames += 1;
+        let elapsed = stats_start.elapsed();
+        if elapsed >= Duration::from_secs(10) {
+            let bytes: u64 = strips.iter().map(|strip| strip.output.bytes_written()).sum();
+            let secs = elapsed.as_secs_f64();
+            info!(
+                "{:.1} fps, {:.2} ms average frame time, {:.0} bytes/s",
+                stats_frames as f64 / secs,
+                stats_compute.as_secs_f64() * 1000. / stats_frames as f64,
+                (bytes - stats_bytes) as f64 / secs,
+            );
+            stats_start = Instant::now();
+            stats_frames = 0;
+            stats_compute = Duration::from_secs(0);
+            stats_bytes = bytes;
+        }
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        for strip in &mut strips {
+            if let Err(e) = strip.output.clear() {
+                error!("Failed to clear {} on shutdown: {}", strip.name, e);
+            }
+        }
+    }).await;
+    if let Err(e) = result {
+        error!("Serial writer failed during shutdown: {}", e);
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LastPort {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl LastPort {
+    pub fn new(name: &str, ports: &[serialport::SerialPortInfo]) -> LastPort {
+        let usb = ports.iter().find(|p| p.port_name == name).and_then(|p| match &p.port_type {
+            SerialPortType::UsbPort(info) => Some(info),
+            _ => None,
+        });
+        LastPort {
+            name: name.to_string(),
+            vid: usb.map(|info| info.vid),
+            pid: usb.map(|info| info.pid),
+            serial_number: usb.and_then(|info| info.serial_number.clone()),
+        }
+    }
+
+    pub fn matches(&self, port: &serialport::SerialPortInfo) -> bool {
+        match (&port.port_type, self.vid, self.pid) {
+            (SerialPortType::UsbPort(info), Some(vid), Some(pid)) => {
+                info.vid == vid && info.pid == pid && info.serial_number == self.serial_number
+            },
+            _ => port.port_name == self.name,
+        }
+    }
+
+    pub fn load(path: &Path) -> Option<LastPort> {
+        let file = std::fs::File::open(path).ok()?;
+        serde_json::from_reader(std::io::BufReader::new(file)).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+fn last_port_path() -> Option<PathBuf> {
+    AppDirs::new(Some("rust_rgb"), AppUI::CommandLine).map(|dirs| dirs.state_dir.join("last_port.json"))
+}
+
+fn select_port(explicit: Option<String>) -> String {
+    let ps = serialport::available_ports().expect("Failed to get serial ports");
+    let path = last_port_path();
+
+    let name = match explicit {
+        Some(name) => name,
+        None => {
+            let last = path.as_deref().and_then(LastPort::load);
+            if let Some(port) = last.and_then(|last| ps.iter().find(|p| last.matches(p))) {
+                info!("Using last serial port {}", port.port_name);
+                return port.port_name.clone();
+            }
+            prompt_port(&ps)
+        },
+    };
+
+    if let Some(path) = &path {
+        if let Err(e) = LastPort::new(&name, &ps).save(path) {
+            warn!("Failed to save last serial port: {}", e);
+        }
+    }
+    name
+}
+
+fn prompt_port(ps: &[serialport::SerialPortInfo]) -> String {
+    for i in 0..ps.len() {
+        let port = &ps[i];
+        println!("{}: {}", i, match &port.port_type {
+            SerialPortType::UsbPort(info) => match &info.product {
+                Some(p) => format!("{}, ({})", p, port.port_name),
+                _ => port.port_name.clone(),
+            },
+            _ => port.port_name.clone(),
+        });
+    }
+
+    loop {
+        let mut s = String::new();
+        std::io::stdin().read_line(&mut s).expect("Failed to read input");
+        if let Ok(i) = s.trim().parse::<usize>() {
+            if let Some(p) = ps.get(i) {
+                break p.port_name.clone();
+            }
+            println!("No index");
+        } 
+        println!("Enter a valid index");
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = Args::parse()?;
+
+    let path = config_path();
+    let mut config = match &path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(fps) = args.fps_cap {
+        config.fps = fps;
+    }
+    if let Some(baud_rate) = args.baud_rate {
+        config.serial.baud_rate = baud_rate;
+        config.serial.probe_bauds.clear();
+    }
+    let serial_settings = config.serial.port_settings()?;
+
+    let ports: Vec<String> = if !config.outputs.is_empty() {
+        config.outputs.iter().map(|output| output.port.clone()).collect()
+    } else if args.output == OutputKind::Serial {
+        vec![select_port(args.port.clone())]
+    } else {
+        vec![args.port.clone().unwrap_or_else(|| "simulated".to_string())]
+    };
+    for port in &ports {
+        info!("Beginning to send data on {}", port);
+    }
+
+    let mut outputs: Vec<(String, Box<dyn Output>)> = ports.into_iter()
+        .map(|port| {
+            let output = open_output(args.output, &port, &serial_settings, &config.serial.probe_bauds);
+            (port, output)
+        })
+        .collect();
+
+    if args.self_test {
+        for (port, output) in &mut outputs {
+            output.self_test(config.output(port).length)?;
+        }
+        return Ok(());
+    }
+
+    let hot_reload = config.hot_reload;
+    let (preview_tx, preview_rx) = if config.preview {
+        let (tx, rx) = watch::channel(Vec::new());
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let config = Arc::new(Mutex::new(config));
+    if hot_reload {
+        if let Some(path) = path {
+            tokio::spawn(watch_config(path, Arc::clone(&config)));
+        }
+    }
+
+    let (state_tx, state_rx) = watch::channel(Arc::new(GameState::default()));
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let publisher = Arc::new(StatePublisher {
+        state: state_tx,
+        latest: state_rx.clone(),
+        events: event_tx,
+        merged: Mutex::new(serde_json::Value::Null),
+    });
+
+    let (recorder, recording) = match &args.record {
+        Some(path) => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(tokio::spawn(record(path.clone(), rx))))
+        },
+        None => (None, None),
+    };
+
+    let c1 = Arc::clone(&config);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let sd = Arc::clone(&shutdown);
+    let status = Arc::new(LightsStatus {
+        enabled: AtomicBool::new(true),
+        connected: AtomicBool::new(true),
+    });
+
+    let lights = tokio::spawn(do_lights(outputs, config, state_rx, event_rx, sd, Arc::clone(&status), preview_tx));
+
+    if let Some(path) = &args.replay {
+        tokio::select! {
+            result = replay(path, args.replay_rate, &c1, &publisher) => {
+                if let Err(e) = result {
+                    error!("Replay failed: {}", e);
                 }
+            },
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    } else if let Err(e) = hyper::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 3000))).serve(hyper::service::make_service_fn(|_conn| {
+        let c1 = Arc::clone(&c1);
+        let p1 = Arc::clone(&publisher);
+        let r1 = recorder.clone();
+        let f1 = preview_rx.clone();
+        let s1 = Arc::clone(&status);
+        async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                let c1 = Arc::clone(&c1);
+                let p1 = Arc::clone(&p1);
+                let r1 = r1.clone();
+                let f1 = f1.clone();
+                let s1 = Arc::clone(&s1);
+                handle_http(req, c1, p1, r1, f1, s1)
+            }))
+        }
+    })).with_graceful_shutdown(async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    }).await {
+        error!("Server error: {}", e);
+    }
+
+    drop(recorder);
+    if let Some(recording) = recording {
+        match recording.await {
+            Ok(Err(e)) => error!("Recording failed: {}", e),
+            Err(e) => error!("Recording task failed: {}", e),
+            Ok(Ok(())) => (),
+        }
+    }
+
+    shutdown.store(true, Ordering::SeqCst);
+    lights.await.expect("Light task panicked");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live_state(weapon_type: &str, clip: i32) -> GameState {
+        serde_json::from_value(live_json(weapon_type, clip)).unwrap()
+    }
+
+    fn live_json(weapon_type: &str, clip: i32) -> serde_json::Value {
+        let team = serde_json::json!({
+            "consecutive_round_losses": 0,
+            "matches_won_this_series": 0,
+            "score": 0,
+            "timeouts_remaining": 1,
+        });
+        serde_json::json!({
+            "provider": { "appid": 730, "name": "Counter-Strike", "steamid": "1", "timestamp": 0, "version": 1 },
+            "map": {
+                "current_spectators": 0,
+                "mode": "competitive",
+                "name": "de_dust2",
+                "num_matches_to_win_series": 0,
+                "phase": "live",
+                "round": 1,
+                "souvenirs_total": 0,
+                "team_ct": team,
+                "team_t": team,
+            },
+            "round": { "phase": "live" },
+            "player": {
+                "activity": "playing",
+                "name": "player",
+                "steamid": "1",
+                "team": "CT",
+                "state": {
+                    "armor": 0, "burning": 0, "equip_value": 0, "flashed": 0, "health": 50, "helmet": false,
+                    "money": 800, "round_killhs": 0, "round_kills": 0, "smoked": 0,
+                },
+                "weapons": {
+                    "weapon_0": {
+                        "ammo_clip": clip, "ammo_clip_max": 30, "ammo_reserve": 90,
+                        "name": "weapon_m4a1", "paintkit": "default", "state": "active", "type": weapon_type,
+                    },
+                },
+            },
+        })
+    }
+
+    fn diff(body: serde_json::Value) -> Vec<EventType> {
+        let mut events = Vec::new();
+        apply_update(&mut GameState::default(), serde_json::from_value(body).unwrap(), &mut events);
+        events
+    }
+
+    fn publisher() -> (StatePublisher, mpsc::UnboundedReceiver<EventType>) {
+        let (state, latest) = watch::channel(Arc::new(GameState::default()));
+        let (events, rx) = mpsc::unbounded_channel();
+        (StatePublisher { state, latest, events, merged: Mutex::new(serde_json::Value::Null) }, rx)
+    }
+
+    fn post(body: &serde_json::Value, config: &Mutex<Config>, publisher: &StatePublisher) {
+        update_state(&serde_json::to_vec(body).unwrap(), config, publisher);
+    }
+
+    fn render_ctx(config: &Config, display: DisplayState) -> RenderCtx<'_> {
+        RenderCtx {
+            config,
+            now: Instant::now(),
+            time: 0.,
+            knife_time: 0.,
+            planted_time: None,
+            display,
+            info: true,
+            gameplay: true,
+            team_color: None,
+            round_over: None,
+            defuse_progress: 0.,
+            health: 0.,
+            armor: 0.,
+            ammo: 0.,
+            flashed: 0.,
+            reactions: &[],
+        }
+    }
+
+    fn render(state: &GameState, ctx: &RenderCtx, len: usize) -> Vec<Color> {
+        let mut cols = vec![Color::black(); len];
+        render_frame(state, ctx, &mut cols);
+        render_overlays(state, ctx, &mut cols);
+        cols
+    }
+
+    fn assert_colors_approx(actual: &[Color], expected: &[Color], eps: f32) {
+        assert_eq!(actual.len(), expected.len(), "frame lengths differ");
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!(a.approx_eq(e, eps), "pixel {} = {}, expected {}", i, a, e);
+        }
+    }
+
+    fn write_options() -> WriteOptions {
+        WriteOptions {
+            diff: false,
+            high_depth: false,
+            keepalive: Duration::from_secs(1),
+            chunk_size: 0,
+            chunk_delay: Duration::from_millis(0),
+            wait_for_ack: false,
+            firmware_gamma: false,
+        }
+    }
+
+    fn encode(instruction: &Instruction) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = Vec::new();
+        instruction.write(&mut out, &mut buf).unwrap();
+        assert_eq!(out.len(), instruction.encoded_len());
+        out
+    }
+
+    #[test]
+    fn instruction_bytes() {
+        let col = Color(1., 0., 0.2);
+        assert_eq!(encode(&Instruction::Show), [0, 0]);
+        assert_eq!(encode(&Instruction::Clear), [1, 0]);
+        assert_eq!(encode(&Instruction::SetPixelColor(0x0102, col)), [2, 0, 0x02, 0x01, 255, 0, 51]);
+        assert_eq!(encode(&Instruction::SetPixelColorGamma(0x0304, col)), [3, 0, 0x04, 0x03, 255, 0, 51]);
+        assert_eq!(encode(&Instruction::FillRange(0x0102, 0x0304, col)), [7, 0, 0x02, 0x01, 0x04, 0x03, 255, 0, 51]);
+        assert_eq!(encode(&Instruction::Ping), [8, 0]);
+    }
+
+    #[test]
+    fn live_frame_shows_health_bar() {
+        let config = Config::default();
+        let state = live_state("Rifle", 30);
+        let ctx = RenderCtx { health: 0.5, ..render_ctx(&config, DisplayState::Live) };
+        let cols = render(&state, &ctx, 10);
+
+        let mut expected = vec![palette::HEALTH; 5];
+        expected.resize(10, Color::black());
+        assert_eq!(cols, expected);
+
+        let mut out = Vec::new();
+        assert!(send_frame(&mut out, &mut Vec::new(), &cols, &mut Vec::new(), write_options(), false).unwrap());
+        let mut frame = encode(&Instruction::SetPixels(&cols));
+        frame.extend(encode(&Instruction::Show));
+        assert_eq!(out, frame);
+    }
+
+    #[test]
+    fn flashbang_covers_hud() {
+        let config = Config::default();
+        let state = live_state("Rifle", 30);
+        let ctx = RenderCtx { health: 1., flashed: 255., ..render_ctx(&config, DisplayState::Live) };
+        assert_eq!(render(&state, &ctx, 4), vec![config.effects.flashbang; 4]);
+    }
+
+    fn line(from: f32, to: f32) -> Vec<Color> {
+        let mut cols = vec![Color::black(); 4];
+        draw_line(&mut cols, from, to, Color::white(), BlendMode::Replace);
+        cols
+    }
+
+    #[test]
+    fn draw_line_coverage() {
+        let (b, w) = (Color::black(), Color::white());
+        assert_eq!(line(1., 3.), [b, w, w, b]);
+        assert_colors_approx(&line(1.25, 1.75), &[b, 0.5 * w, b, b], 1e-6);
+        assert_colors_approx(&line(0.5, 2.25), &[0.5 * w, w, 0.25 * w, b], 1e-6);
+    }
+
+    #[test]
+    fn draw_line_clips_to_strip() {
+        let (b, w) = (Color::black(), Color::white());
+        assert_eq!(line(-1.5, 1.), [w, b, b, b]);
+        assert_colors_approx(&line(2.5, 10.), &[b, b, 0.5 * w, w], 1e-6);
+        assert_eq!(line(-3., -1.), [b; 4]);
+        assert_eq!(line(5., 6.), [b; 4]);
+    }
+
+    #[test]
+    fn draw_line_ignores_empty_spans() {
+        let gray = Color(0.5, 0.5, 0.5);
+        for &(from, to) in &[(2., 2.), (3., 1.), (1.5, 1.5)] {
+            let mut cols = vec![gray; 4];
+            draw_line(&mut cols, from, to, Color::white(), BlendMode::Add);
+            assert_eq!(cols, [gray; 4]);
+        }
+    }
+
+    #[test]
+    fn orientation() {
+        let (r, g, b) = (Color::red(), Color(0., 1., 0.), Color::blue());
+        let mut frame = vec![r, g, b, Color::black()];
+        orient(&mut frame, false, true);
+        assert_eq!(frame, [Color::black(), b, g, r]);
+
+        for len in [4, 5] {
+            let mut frame = vec![Color::black(); len];
+            frame[0] = r;
+            frame[1] = g;
+            frame[2] = b;
+            orient(&mut frame, true, false);
+            let reversed: Vec<Color> = frame.iter().rev().copied().collect();
+            assert_eq!(frame, reversed);
+            assert_eq!(frame[0], if len == 4 { g } else { b });
+        }
+    }
+
+    #[test]
+    fn end_anchored_bar_fills_from_the_end() {
+        let mut cols = vec![Color::black(); 4];
+        draw_bar(&mut cols, 0.3, BarAnchor::End, Color::white(), BlendMode::Replace);
+        assert_colors_approx(&cols, &[Color::black(), Color::black(), 0.2 * Color::white(), Color::white()], 1e-5);
+    }
+
+    #[test]
+    fn freezetime_region_is_reserved() {
+        let config = Config {
+            freezetime_region: Some((6, usize::MAX)),
+            freezetime: FreezetimeDisplay::Money,
+            ..Config::default()
+        };
+        let mut state = live_state("Rifle", 30);
+        state.round.as_mut().unwrap().phase = "freezetime".to_string();
+        let ctx = RenderCtx { health: 1., ..render_ctx(&config, DisplayState::Freezetime) };
+        let cols = render(&state, &ctx, 10);
+
+        let money = 800. / MAX_MONEY * 4.;
+        let mut expected = vec![palette::HEALTH; 6];
+        expected.push(money * palette::MONEY);
+        expected.resize(10, Color::black());
+        assert_colors_approx(&cols, &expected, 1e-5);
+    }
+
+    #[test]
+    fn speed_scale_halves_effect_period() {
+        let effect = EventEffect { color: Color::white(), duration: 1., blend: BlendMode::Mix, shape: EffectShape::Fill };
+        let reactions = [ActiveReaction { event: None, reaction: Reaction::Flash(effect), start: 0., reverse: false }];
+        let state = GameState::default();
+
+        let normal = Config::default();
+        let ctx = RenderCtx { time: 0.5, reactions: &reactions, ..render_ctx(&normal, DisplayState::Idle) };
+        let expected = render(&state, &ctx, 3);
+
+        let fast = Config { speed_scale: 2., ..Config::default() };
+        let ctx = RenderCtx { time: 0.25, reactions: &reactions, ..render_ctx(&fast, DisplayState::Idle) };
+        assert_colors_approx(&render(&state, &ctx, 3), &expected, 1e-5);
+        assert!(reactions[0].elapsed(0.5, &fast) >= effect.duration);
+    }
+
+    #[test]
+    fn mvp_clears_on_death_and_disconnect() {
+        let config = Config::default();
+        let state = live_state("Rifle", 30);
+        let mut effects = EffectState::default();
+
+        effects.handle_event(EventType::MVP, 0., &config, &state);
+        effects.update(&state, 0.1, &config);
+        assert!(effects.mvp.is_some());
+        effects.handle_event(EventType::Death, 0.2, &config, &state);
+        assert!(effects.mvp.is_none());
+
+        effects.handle_event(EventType::MVP, 1., &config, &state);
+        effects.update(&GameState::default(), 1.1, &config);
+        assert!(effects.mvp.is_none());
+        assert_eq!(DisplayState::from_state(&GameState::default(), effects.mvp.is_some(), &config.themes), DisplayState::Idle);
+    }
+
+    #[test]
+    fn wrong_appid_is_ignored() {
+        let config = Mutex::new(Config::default());
+        let (publisher, _events) = publisher();
+
+        let mut body = live_json("Rifle", 30);
+        body["provider"]["appid"] = 570.into();
+        post(&body, &config, &publisher);
+        assert!(publisher.latest.borrow().map.is_none());
+
+        post(&live_json("Rifle", 30), &config, &publisher);
+        assert!(publisher.latest.borrow().map.is_some());
+    }
+
+    #[test]
+    fn rejected_update_is_not_merged() {
+        let config = Mutex::new(Config { merge_updates: true, ..Config::default() });
+        let (publisher, _events) = publisher();
+        post(&live_json("Rifle", 30), &config, &publisher);
+        let merged = publisher.merged.lock().unwrap().clone();
+
+        let mut body = live_json("Pistol", 5);
+        body["provider"]["appid"] = 570.into();
+        post(&body, &config, &publisher);
+        let mut body = live_json("Pistol", 5);
+        body.as_object_mut().unwrap().remove("provider");
+        post(&body, &config, &publisher);
+
+        assert_eq!(*publisher.merged.lock().unwrap(), merged);
+        assert_eq!(publisher.latest.borrow().active_weapon().unwrap().1.r#type, "Rifle");
+    }
+
+    #[test]
+    fn cs2_payload_with_missing_and_added_fields() {
+        // CS2 leaves out match_stats and weapons here and reports the new bomb field under added
+        let player = serde_json::json!({
+            "activity": "playing",
+            "name": "player",
+            "steamid": "1",
+            "state": {
+                "armor": 0, "burning": 0, "equip_value": 0, "flashed": 0, "health": 100, "helmet": false,
+                "money": 800, "round_killhs": 0, "round_kills": 0, "smoked": 0,
+            },
+        });
+        let planted = diff(serde_json::json!({
+            "player": player,
+            "round": { "phase": "live", "bomb": "planted" },
+            "previously": { "player": { "state": {} }, "round": {} },
+            "added": { "round": { "bomb": true } },
+        }));
+        assert_eq!(planted, [EventType::BombPlanted]);
+
+        let defused = diff(serde_json::json!({
+            "player": player,
+            "round": { "phase": "over", "bomb": "defused", "win_team": "CT" },
+            "previously": { "round": { "bomb": "planted", "phase": "live" } },
+        }));
+        assert_eq!(defused, [EventType::BombDefused]);
+
+        let quiet = diff(serde_json::json!({
+            "player": player,
+            "round": { "phase": "live" },
+            "previously": { "player": { "weapons": {}, "match_stats": { "kills": 0 } }, "round": { "phase": "freezetime" } },
+        }));
+        assert!(quiet.is_empty());
+    }
+
+    #[test]
+    fn partial_update_keeps_earlier_fields() {
+        let mut merged = serde_json::Value::Null;
+        merge_update(&mut merged, serde_json::json!({
+            "map": { "phase": "live", "round": 3 },
+            "player": { "name": "a", "state": { "health": 100 } },
+        }));
+        let value = merge_update(&mut merged, serde_json::json!({
+            "player": { "state": { "health": 40 } },
+            "previously": { "player": { "state": { "health": 100 } } },
+        }));
+        assert_eq!(value["map"]["phase"], "live");
+        assert_eq!(value["player"]["name"], "a");
+        assert_eq!(value["player"]["state"]["health"], 40);
+        assert_eq!(value["previously"]["player"]["state"]["health"], 100);
+        assert!(merged.get("previously").is_none());
+    }
+
+    #[test]
+    fn update_prunes_removed_fields() {
+        let mut merged = serde_json::Value::Null;
+        merge_update(&mut merged, serde_json::json!({
+            "map": { "phase": "live" },
+            "player": { "weapons": { "weapon_0": { "state": "holstered" }, "weapon_1": { "state": "active" } } },
+        }));
+        let value = merge_update(&mut merged, serde_json::json!({
+            "player": { "weapons": { "weapon_0": { "state": "active" } } },
+            "previously": {
+                "map": { "phase": "live" },
+                "player": { "weapons": { "weapon_0": { "state": "holstered" }, "weapon_1": { "state": "active" } } },
+            },
+        }));
+        assert!(value.get("map").is_none());
+        assert_eq!(value["player"]["weapons"]["weapon_0"]["state"], "active");
+        assert!(value["player"]["weapons"].get("weapon_1").is_none());
+    }
+
+    #[test]
+    fn damage_reaction_is_configurable() {
+        let config: Config = serde_json::from_str(r#"{ "reactions": { "TookDamage": { "effect": "none" }, "Kill": { "effect": "chase" } } }"#).unwrap();
+        assert!(matches!(config.reaction(EventType::TookDamage(30)), Some(Reaction::Nothing)));
+        assert!(matches!(config.reaction(EventType::Kill), Some(Reaction::Chase)));
+        assert!(serde_json::from_str::<Config>(r#"{ "reactions": { "Jump": { "effect": "none" } } }"#).is_err());
+    }
+
+    #[test]
+    fn serpentine_mapping() {
+        let layout = MatrixLayout { width: 3, height: 3, serpentine: true };
+        let indices: Vec<usize> = (0..3).flat_map(|y| (0..3).map(move |x| (x, y))).map(|(x, y)| layout.map_xy(x, y)).collect();
+        assert_eq!(indices, [0, 1, 2, 5, 4, 3, 6, 7, 8]);
+
+        let progressive = MatrixLayout { serpentine: false, ..layout };
+        assert_eq!(progressive.map_xy(0, 1), 3);
+        assert_eq!(progressive.map_xy(2, 1), 5);
+    }
+
+    #[test]
+    fn remap_moves_rows_to_wired_positions() {
+        let layout = MatrixLayout { width: 3, height: 2, serpentine: true };
+        let logical: Vec<Color> = (0..7).map(|i| Color(i as f32 / 10., 0., 0.)).collect();
+        let mut frame = logical.clone();
+        let mut scratch = Vec::new();
+        layout.remap(&mut frame, &mut scratch);
+        let order = [0, 1, 2, 5, 4, 3, 6];
+        let expected: Vec<Color> = order.iter().map(|&i| logical[i]).collect();
+        assert_eq!(frame, expected);
+
+        let progressive = MatrixLayout { serpentine: false, ..layout };
+        let mut frame = logical.clone();
+        progressive.remap(&mut frame, &mut scratch);
+        assert_eq!(frame, logical);
+    }
+
+    #[test]
+    fn pixel_bytes_are_grb() {
+        let cols = [Color(1., 0., 0.2), Color(0., 1., 0.)];
+        assert_eq!(encode(&Instruction::SetPixels(&cols)), [4, 0, 0, 255, 51, 255, 0, 0]);
+        assert_eq!(encode(&Instruction::SetPixelsGamma(&cols)), [6, 0, 0, 255, 51, 255, 0, 0]);
+        assert_eq!(encode(&Instruction::SetPixels16(&cols[..1])), [5, 0, 0, 0, 255, 255, 0x33, 0x33]);
+    }
+
+    #[test]
+    fn per_channel_gamma_bytes() {
+        let gamma: Gamma = serde_json::from_str("[1.0, 2.0, 3.0]").unwrap();
+        let gray = [Color(0.5, 0.5, 0.5).with_gamma(gamma.channels())];
+        assert_eq!(encode(&Instruction::SetPixels(&gray)), [4, 0, 64, 128, 32]);
+
+        let uniform: Gamma = serde_json::from_str("2.0").unwrap();
+        let gray = [Color(0.5, 0.5, 0.5).with_gamma(uniform.channels())];
+        assert_eq!(encode(&Instruction::SetPixels(&gray)), [4, 0, 64, 64, 64]);
+    }
+
+
+    #[test]
+    fn diff_sends_fewer_bytes_for_small_changes() {
+        let options = WriteOptions { diff: true, ..write_options() };
+        let mut cols = vec![Color(0.5, 0.2, 0.1); 30];
+        let mut sent = Vec::new();
+        let mut full = Vec::new();
+        send_frame(&mut full, &mut Vec::new(), &cols, &mut sent, options, false).unwrap();
+
+        cols[7] = Color::white();
+        let mut single = Vec::new();
+        assert!(send_frame(&mut single, &mut Vec::new(), &cols, &mut sent, options, false).unwrap());
+        let mut expected = encode(&Instruction::SetPixelColor(7, Color::white()));
+        expected.extend(encode(&Instruction::Show));
+        assert_eq!(single, expected);
+        assert!(single.len() < full.len());
+
+        let mut unchanged = Vec::new();
+        assert!(!send_frame(&mut unchanged, &mut Vec::new(), &cols, &mut sent, options, false).unwrap());
+        assert!(unchanged.is_empty());
+    }
+
+
+    #[test]
+    fn encode_into_reuses_buffer() {
+        let mut buf = vec![9; 64];
+        ColorFormat::GRB.encode_into(&[Color(1., 0., 0.2)], &mut buf);
+        assert_eq!(&buf[64..], [0, 255, 51]);
+
+        let mut out = Vec::new();
+        let cols = [Color(0., 0., 1.)];
+        Instruction::SetPixels(&[Color::white(); 8]).write(&mut out, &mut buf).unwrap();
+        out.clear();
+        Instruction::SetPixels(&cols).write(&mut out, &mut buf).unwrap();
+        assert_eq!(out, [4, 0, 0, 0, 255]);
+    }
+
+
+    #[test]
+    fn stale_state_fades_to_idle() {
+        let config = Config { idle_timeout: 5., ..Config::default() };
+        let mut state = live_state("Rifle", 30);
+        let received = Instant::now();
+        state.received = Some(received);
+        let at = |secs: f64| {
+            let ctx = RenderCtx { health: 1., now: received + Duration::from_secs_f64(secs), ..render_ctx(&config, DisplayState::Live) };
+            render(&state, &ctx, 2)
+        };
+
+        assert_eq!(at(2.), [palette::HEALTH; 2]);
+        assert_colors_approx(&at(5.5), &[0.5 * palette::HEALTH; 2], 1e-5);
+        assert_eq!(at(10.), [Color::black(); 2]);
+    }
+
+
+    #[test]
+    fn weapon_colors_fall_back_to_default() {
+        let mut config = Config::default();
+        config.weapon_colors.insert("Rifle".to_string(), Color::blue());
+        assert_eq!(config.weapon_color("Rifle"), Color::blue());
+        assert_eq!(config.weapon_color("Pistol"), config.default_weapon_color);
+        assert_eq!(config.weapon_color("Taser"), config.default_weapon_color);
+    }
+
+
+    fn low_ammo_blinks(weapon_type: &str, clip: Option<i32>) -> bool {
+        let config = Config::default();
+        let mut body = live_json(weapon_type, 0);
+        body["player"]["weapons"]["weapon_0"]["ammo_clip"] = serde_json::json!(clip);
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let ammo = clip.map_or(0., |clip| clip as f32 / 30.);
+        let ctx = RenderCtx { ammo, ..render_ctx(&config, DisplayState::Live) };
+        render(&state, &ctx, 10)[0] == palette::LOW_AMMO
+    }
+
+    #[test]
+    fn low_ammo_threshold() {
+        assert!(low_ammo_blinks("Rifle", Some(1)));
+        assert!(low_ammo_blinks("Rifle", Some(5)));
+        assert!(!low_ammo_blinks("Rifle", Some(6)));
+        assert!(!low_ammo_blinks("Rifle", Some(30)));
+        assert!(!low_ammo_blinks("Grenade", None));
+        assert!(!low_ammo_blinks("C4", None));
+    }
+
+
+    #[test]
+    fn websocket_handshake_headers() {
+        let request = |upgrade: &str, version: &str| {
+            Request::builder()
+                .header(hyper::header::UPGRADE, upgrade)
+                .header(hyper::header::SEC_WEBSOCKET_VERSION, version)
+                .header(hyper::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .body(())
+                .unwrap()
+        };
+        let accept = websocket_accept(request("WebSocket", "13").headers());
+        assert_eq!(accept.as_deref(), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(websocket_accept(request("h2c", "13").headers()).is_none());
+        assert!(websocket_accept(request("websocket", "8").headers()).is_none());
+        assert!(websocket_accept(Request::builder().body(()).unwrap().headers()).is_none());
+    }
+
+
+    #[test]
+    fn score_segments_are_proportional() {
+        let mut cols = vec![Color::black(); 20];
+        draw_score(&mut cols, 5, 2, 10);
+        let mut expected = vec![palette::CT; 5];
+        expected.resize(18, Color::black());
+        expected.resize(20, palette::T);
+        assert_colors_approx(&cols, &expected, 1e-5);
+
+        let mut cols = vec![Color::black(); 20];
+        draw_score(&mut cols, 13, 0, 10);
+        let mut expected = vec![palette::CT; 10];
+        expected.resize(20, Color::black());
+        assert_colors_approx(&cols, &expected, 1e-5);
+    }
+
+
+    #[test]
+    fn round_wins_draw_one_segment_each() {
+        let mut body = live_json("Rifle", 30);
+        body["round"]["phase"] = "freezetime".into();
+        body["map"]["round_wins"] = serde_json::json!({
+            "1": "ct_win_elimination",
+            "2": "t_win_bomb",
+            "10": "t_win_defuse",
+            "3": "ct_win_time",
+        });
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let config = Config { freezetime: FreezetimeDisplay::RoundWins, ..Config::default() };
+        let cols = render(&state, &render_ctx(&config, DisplayState::Freezetime), 8);
+
+        let segments = [
+            RoundWinKind::Elimination.color(Team::CT),
+            RoundWinKind::Bomb.color(Team::T),
+            RoundWinKind::Time.color(Team::CT),
+            RoundWinKind::Defuse.color(Team::T),
+        ];
+        let expected: Vec<Color> = segments.iter().flat_map(|&c| vec![c; 2]).collect();
+        assert_colors_approx(&cols, &expected, 1e-5);
+    }
+
+
+    #[test]
+    fn power_limit_scales_to_cap() {
+        let mut cols = vec![Color::white(); 10];
+        limit_power(&mut cols, 6.);
+        let total: f32 = cols.iter().map(|c| c.0 + c.1 + c.2).sum();
+        assert!((total - 6.).abs() < 1e-4);
+        assert_colors_approx(&cols, &[0.2 * Color::white(); 10], 1e-5);
+
+        let dim = vec![Color(0.1, 0.2, 0.), Color(0., 0., 0.3)];
+        let mut cols = dim.clone();
+        limit_power(&mut cols, 6.);
+        assert_colors_approx(&cols, &dim, 0.);
+    }
+
+
+    #[test]
+    fn dithering_averages_fractional_values() {
+        let value = 0.25 / 255.;
+        let mut error = (0., 0., 0.);
+        let mut total = 0.;
+        for _ in 0..100 {
+            let byte = Color(value, 0., 0.).dither(&mut error).as_byte_color().0;
+            assert!(byte <= 1);
+            total += byte as f32;
+        }
+        assert!((total / 100. - 0.25).abs() < 0.01);
+    }
+
+
+    #[test]
+    fn high_depth_channels_are_little_endian() {
+        let cols = [
+            Color(0., 1., 0.5),
+            Color(0.25, 1. / 65535., 1.5),
+            Color(-0.1, 0., 0.),
+        ];
+        assert_eq!(cols[0].as_u16_color(), (0, 65535, 32768));
+        assert_eq!(cols[1].as_u16_color(), (16384, 1, 65535));
+        assert_eq!(cols[2].as_u16_color(), (0, 0, 0));
+        assert_eq!(encode(&Instruction::SetPixels16(&cols)), [
+            5, 0,
+            0xff, 0xff, 0x00, 0x00, 0x00, 0x80,
+            0x01, 0x00, 0x00, 0x40, 0xff, 0xff,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+
+    #[test]
+    fn transient_events_are_coalesced() {
+        let mut events = Vec::new();
+        for event in [EventType::Shoot, EventType::Kill, EventType::Shoot, EventType::SwitchWeapon, EventType::Kill, EventType::Shoot, EventType::SwitchWeapon] {
+            push_event(&mut events, event);
+        }
+        assert_eq!(events, [EventType::Shoot, EventType::Kill, EventType::SwitchWeapon, EventType::Kill]);
+    }
+
+
+    #[test]
+    fn crossfade_blends_from_previous_frame() {
+        let from = vec![Color(1., 0., 0.2); 4];
+        let to = vec![Color(0., 0.5, 0.6); 4];
+
+        let mut cols = to.clone();
+        crossfade(&mut cols, &from, 0.5);
+        assert_colors_approx(&cols, &[Color(0.5, 0.25, 0.4); 4], 1e-6);
+
+        let mut cols = to.clone();
+        crossfade(&mut cols, &from, 0.);
+        assert_colors_approx(&cols, &from, 0.);
+        let mut cols = to.clone();
+        crossfade(&mut cols, &from, 1.);
+        assert_colors_approx(&cols, &to, 0.);
+    }
+
+
+    #[test]
+    fn display_state_from_phases() {
+        let cases: &[(&str, &str, Option<&str>, bool, DisplayState)] = &[
+            ("warmup", "live", None, false, DisplayState::Warmup),
+            ("intermission", "over", None, false, DisplayState::Intermission),
+            ("live", "freezetime", None, true, DisplayState::Freezetime),
+            ("live", "over", Some("CT"), true, DisplayState::Mvp),
+            ("live", "over", Some("CT"), false, DisplayState::RoundOver(Team::CT)),
+            ("live", "over", Some("T"), false, DisplayState::RoundOver(Team::T)),
+            ("live", "live", None, false, DisplayState::Live),
+            ("live", "over", None, false, DisplayState::Live),
+        ];
+        let themes = PhaseThemes::default();
+        for &(map_phase, round_phase, win_team, mvp, expected) in cases {
+            let mut body = live_json("Rifle", 30);
+            body["map"]["phase"] = map_phase.into();
+            body["round"]["phase"] = round_phase.into();
+            if let Some(team) = win_team {
+                body["round"]["win_team"] = team.into();
+            }
+            let state: GameState = serde_json::from_value(body).unwrap();
+            assert_eq!(DisplayState::from_state(&state, mvp, &themes), expected, "{} {} {:?}", map_phase, round_phase, win_team);
+        }
+
+        let mut body = live_json("Knife", 0);
+        body["map"]["round"] = 0.into();
+        let state: GameState = serde_json::from_value(body).unwrap();
+        assert_eq!(DisplayState::from_state(&state, false, &themes), DisplayState::KnifeRound);
+
+        assert_eq!(DisplayState::from_state(&GameState::default(), false, &themes), DisplayState::Idle);
+        let mut body = live_json("Rifle", 30);
+        body.as_object_mut().unwrap().remove("round");
+        let state: GameState = serde_json::from_value(body).unwrap();
+        assert_eq!(DisplayState::from_state(&state, false, &themes), DisplayState::Idle);
+    }
+
+
+    #[test]
+    fn out_of_range_pixels_are_skipped() {
+        let mut cols = vec![Color::black(); 70000];
+        let mut sent = vec![(0, 0, 0); cols.len()];
+        cols[3] = Color::white();
+        cols[65540] = Color::white();
+        let mut out = Vec::new();
+        assert!(write_frame(&mut out, &mut Vec::new(), &cols, &mut sent, false, false).unwrap());
+
+        let mut expected = encode(&Instruction::SetPixelColor(3, Color::white()));
+        expected.extend(encode(&Instruction::Show));
+        assert_eq!(out, expected);
+        assert_eq!(sent[3], (255, 255, 255));
+        assert_eq!(sent[65540], (0, 0, 0));
+    }
+
+
+    async fn serve(req: Request<Body>, config: Config, status: &Arc<LightsStatus>) -> Response<Body> {
+        let (publisher, _events) = publisher();
+        handle_http(req, Arc::new(Mutex::new(config)), Arc::new(publisher), None, None, status.clone()).await.unwrap()
+    }
+
+    fn lights_status() -> Arc<LightsStatus> {
+        Arc::new(LightsStatus { enabled: AtomicBool::new(true), connected: AtomicBool::new(false) })
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let config = || Config { max_body_size: 16, ..Config::default() };
+        let status = lights_status();
+
+        let req = Request::post("/").body(Body::from(vec![b' '; 17])).unwrap();
+        assert_eq!(serve(req, config(), &status).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(vec![b' '; 10]), Ok(vec![b' '; 10])];
+        let req = Request::post("/").body(Body::wrap_stream(futures::stream::iter(chunks))).unwrap();
+        assert_eq!(serve(req, config(), &status).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/").body(Body::from("{}")).unwrap();
+        assert_eq!(serve(req, config(), &status).await.status(), StatusCode::OK);
+    }
+
+
+    #[test]
+    fn knife_round_detection() {
+        let with_weapons = |types: &[&str]| {
+            let mut body = live_json("Knife", 0);
+            body["map"]["round"] = 0.into();
+            let template = body["player"]["weapons"]["weapon_0"].clone();
+            let weapons: serde_json::Map<_, _> = types.iter().enumerate().map(|(i, &t)| {
+                let mut weapon = template.clone();
+                weapon["type"] = t.into();
+                (format!("weapon_{}", i), weapon)
+            }).collect();
+            body["player"]["weapons"] = weapons.into();
+            serde_json::from_value::<GameState>(body).unwrap()
+        };
+        assert!(with_weapons(&["Knife"]).only_knife());
+        assert!(with_weapons(&["Knife", "C4"]).only_knife());
+        assert!(!with_weapons(&["Knife", "Pistol"]).only_knife());
+        assert!(!with_weapons(&["C4"]).only_knife());
+        assert!(!with_weapons(&[]).only_knife());
+
+        let themes = PhaseThemes::default();
+        let state = with_weapons(&["Knife", "C4"]);
+        assert_eq!(DisplayState::from_state(&state, false, &themes), DisplayState::KnifeRound);
+        let disabled = PhaseThemes { knife_round: false, ..PhaseThemes::default() };
+        assert_eq!(DisplayState::from_state(&state, false, &disabled), DisplayState::Live);
+        let later = PhaseThemes { knife_round_number: 1, ..PhaseThemes::default() };
+        assert_eq!(DisplayState::from_state(&state, false, &later), DisplayState::Live);
+    }
+
+
+    #[test]
+    fn defuse_heuristic() {
+        let defusing = |heuristic: DefuseHeuristic, edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut body = live_json("Rifle", 30);
+            body["round"]["bomb"] = "planted".into();
+            body["player"]["position"] = "1.0, 2.0, 3.0".into();
+            body["player"]["state"]["defusekit"] = true.into();
+            edit(&mut body);
+            let state: GameState = serde_json::from_value(body).unwrap();
+            let themes = PhaseThemes { defuse_heuristic: heuristic, ..PhaseThemes::default() };
+            DisplayState::from_state(&state, false, &themes) == DisplayState::BombDefusing
+        };
+        use DefuseHeuristic::*;
+        assert!(defusing(Stationary, &|_| ()));
+        assert!(!defusing(Stationary, &|body| body["previously"] = serde_json::json!({ "player": { "position": "0.0, 2.0, 3.0" } })));
+        assert!(!defusing(Stationary, &|body| body["player"]["state"]["defusekit"] = false.into()));
+        assert!(defusing(Planted, &|body| body["player"]["state"]["defusekit"] = false.into()));
+        assert!(!defusing(Planted, &|body| body["player"]["team"] = "T".into()));
+        assert!(!defusing(Planted, &|body| body["round"]["bomb"] = serde_json::Value::Null));
+        assert!(!defusing(Off, &|_| ()));
+        assert!(defusing(Off, &|body| body["bomb"] = serde_json::json!({ "state": "defusing", "countdown": "4.0" })));
+    }
+
+
+    #[test]
+    fn spectating_tints_by_observed_team() {
+        let mut body = live_json("Rifle", 30);
+        body["player"]["steamid"] = "2".into();
+        body["player"]["team"] = "T".into();
+        body["player"]["observer_slot"] = 3.into();
+        let state: GameState = serde_json::from_value(body).unwrap();
+        assert!(state.is_spectating());
+
+        let config = Config { spectator_tint: 0.5, ..Config::default() };
+        let cols = render(&state, &render_ctx(&config, DisplayState::Live), 10);
+        assert_colors_approx(&cols[9..], &[0.5 * palette::T], 1e-5);
+    }
+
+
+    #[test]
+    fn serial_settings_map_onto_port_settings() {
+        let settings: SerialSettings = serde_json::from_str(r#"{ "baud_rate": 115200, "data_bits": 7, "parity": "even", "stop_bits": 2, "timeout_ms": 50 }"#).unwrap();
+        let port = settings.port_settings().unwrap();
+        assert_eq!(port.baud_rate, 115200);
+        assert_eq!(port.data_bits, serialport::DataBits::Seven);
+        assert_eq!(port.parity, serialport::Parity::Even);
+        assert_eq!(port.stop_bits, serialport::StopBits::Two);
+        assert_eq!(port.flow_control, serialport::FlowControl::None);
+        assert_eq!(port.timeout, Duration::from_millis(50));
+
+        let default = SerialSettings::default().port_settings().unwrap();
+        assert_eq!(default.baud_rate, 250000);
+        assert_eq!(default.data_bits, serialport::DataBits::Eight);
+        assert_eq!(default.parity, serialport::Parity::None);
+        assert_eq!(default.stop_bits, serialport::StopBits::One);
+
+        for bad in [r#"{ "baud_rate": 0 }"#, r#"{ "data_bits": 9 }"#, r#"{ "parity": "mark" }"#, r#"{ "stop_bits": 3 }"#] {
+            let settings: SerialSettings = serde_json::from_str(bad).unwrap();
+            assert_eq!(settings.port_settings().unwrap_err().kind(), std::io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+
+    #[test]
+    fn win_streak_escalates_and_resets() {
+        let streak = WinStreak { base: 0.5, step: 0.2 };
+        let results = ["ct_win_elimination", "ct_win_time", "ct_win_defuse", "t_win_bomb", "ct_win_elimination"];
+        let mut wins = serde_json::Map::new();
+        let mut intensities = Vec::new();
+        for (round, result) in results.iter().enumerate() {
+            wins.insert((round + 1).to_string(), (*result).into());
+            let mut body = live_json("Rifle", 30);
+            body["map"]["round_wins"] = wins.clone().into();
+            let state: GameState = serde_json::from_value(body).unwrap();
+            intensities.push((state.win_streak(), streak.intensity(state.win_streak())));
+        }
+        let expected = [(1, 0.5), (2, 0.7), (3, 0.9), (0, 0.5), (1, 0.5)];
+        for ((count, intensity), (expected_count, expected_intensity)) in intensities.into_iter().zip(expected) {
+            assert_eq!(count, expected_count);
+            assert!((intensity - expected_intensity).abs() < 1e-6);
+        }
+        assert_eq!(streak.intensity(10), 1.);
+    }
+
+
+    #[test]
+    fn night_mode_multiplier() {
+        let night = NightMode { start: "22:30".to_string(), end: "07:00".to_string(), brightness: 0.2 };
+        assert_eq!(night.multiplier(22 * 60 + 29), 1.);
+        assert_eq!(night.multiplier(22 * 60 + 30), 0.2);
+        assert_eq!(night.multiplier(3 * 60), 0.2);
+        assert_eq!(night.multiplier(7 * 60), 1.);
+        assert_eq!(night.multiplier(12 * 60), 1.);
+
+        let day = NightMode { start: "09:00".to_string(), end: "17:00".to_string(), brightness: 0.5 };
+        assert_eq!(day.multiplier(12 * 60), 0.5);
+        assert_eq!(day.multiplier(20 * 60), 1.);
+
+        let invalid = NightMode { start: "25:00".to_string(), ..day };
+        assert_eq!(invalid.multiplier(12 * 60), 1.);
+    }
+
+
+    #[test]
+    fn twinkle_is_reproducible_from_seed() {
+        let base = Color(0.1, 0.1, 0.1);
+        let sparkle = |seed: u64, time: f64, density: f32| {
+            let mut cols = vec![base; 64];
+            twinkle(&mut cols, time, seed, Color::white(), density, 3.);
+            cols
+        };
+        let first = sparkle(7, 12.3, 0.3);
+        assert_eq!(first, sparkle(7, 12.3, 0.3));
+        assert_ne!(first, sparkle(8, 12.3, 0.3));
+
+        let lit = first.iter().filter(|&&c| c != base).count();
+        assert!(lit > 5 && lit < 40, "{} pixels lit", lit);
+        assert!(first.iter().all(|c| c.0 >= base.0 && c.0 <= base.0 + 1.));
+
+        assert!(sparkle(7, 12.3, 0.).iter().all(|&c| c == base));
+        assert!(sparkle(7, 12.3, 1.).iter().all(|&c| c != base));
+    }
+
+
+    #[test]
+    fn death_wave_collapses_inward() {
+        let death = EventEffects::default().death;
+        let at = |since: f64| {
+            let mut cols = vec![Color::black(); 20];
+            death.draw(&mut cols, since);
+            cols.iter().map(|c| c.0).collect::<Vec<f32>>()
+        };
+        let front = |cols: &[f32]| cols.iter().take_while(|&&r| r > 0.).count();
+
+        assert_eq!(front(&at(0.)), 0);
+        let quarter = at(0.5);
+        assert_eq!(front(&quarter), 5);
+        assert_eq!(quarter.iter().rev().take_while(|&&r| r > 0.).count(), 5);
+        assert_eq!(front(&at(0.75)), 7);
+        assert!(at(1.).iter().all(|&r| r == 1.));
+        assert!(at(2.5).iter().all(|&r| (r - 0.5).abs() < 1e-5));
+        assert!(at(4.).iter().all(|&r| r == 0.));
+    }
+
+
+    #[test]
+    fn bomb_plant_fires_once_for_either_signal() {
+        let plants = |round: serde_json::Value, previously: serde_json::Value, added: serde_json::Value| {
+            let mut body = live_json("Rifle", 30);
+            body["round"] = round;
+            body["previously"] = previously;
+            body["added"] = added;
+            diff(body).iter().filter(|&&e| e == EventType::BombPlanted).count()
+        };
+        let none = serde_json::json!({});
+        assert_eq!(plants(serde_json::json!({ "phase": "live", "bomb": "planted" }), none.clone(), serde_json::json!({ "round": { "bomb": true } })), 1);
+        assert_eq!(plants(serde_json::json!({ "phase": "bomb" }), serde_json::json!({ "round": { "phase": "live" } }), none.clone()), 1);
+        assert_eq!(plants(
+            serde_json::json!({ "phase": "bomb", "bomb": "planted" }),
+            serde_json::json!({ "round": { "phase": "live" } }),
+            serde_json::json!({ "round": { "bomb": true } }),
+        ), 1);
+        assert_eq!(plants(serde_json::json!({ "phase": "bomb", "bomb": "planted" }), none.clone(), none), 0);
+    }
+
+
+    #[test]
+    fn easing_approaches_target() {
+        let mut value = 0.;
+        let mut previous = value;
+        for _ in 0..10 {
+            value = ease(value, 1., 0.01, 0.1);
+            assert!(value > previous && value < 1.);
+            previous = value;
+        }
+        assert!((value - (1. - (-1f32).exp())).abs() < 1e-4);
+        assert!((ease(0.8, 0.2, 10., 0.1) - 0.2).abs() < 1e-6);
+        assert_eq!(ease(0.3, 0.9, 0.01, 0.), 0.9);
+    }
+
+
+    #[test]
+    fn mvp_flourish_is_timed() {
+        let mut config = Config::default();
+        config.themes.mvp_duration = 2.;
+        let state = live_state("Rifle", 30);
+        let display = |effects: &EffectState| DisplayState::from_state(&state, effects.mvp.is_some(), &config.themes);
+        let mut effects = EffectState::default();
+
+        effects.handle_event(EventType::MVP, 10., &config, &state);
+        effects.update(&state, 11.9, &config);
+        assert_eq!(display(&effects), DisplayState::Mvp);
+        effects.update(&state, 12.1, &config);
+        assert_eq!(display(&effects), DisplayState::Live);
+
+        effects.handle_event(EventType::MVP, 20., &config, &state);
+        effects.handle_event(EventType::NewRound, 20.5, &config, &state);
+        assert_eq!(display(&effects), DisplayState::Live);
+    }
+
+
+    struct FlakyWriter {
+        written: Vec<u8>,
+        timeouts: usize,
+        attempts: usize,
+        max_write: usize,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.attempts += 1;
+            if self.timeouts > 0 {
+                self.timeouts -= 1;
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"));
             }
+            let n = buf.len().min(self.max_write);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
         }
 
-        {
-            let guard = state.lock().unwrap();
-            let state: &GameState = &*guard;
-    
-            clear(&mut cols);
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
-            if let Some(map) = &state.map {
-                println!("Map: {}", map.phase);
-                if let Some(round) = &state.round {
-                    if round.phase == "freezetime" {
-                        if let Some(wins) = &map.round_wins {
-                            let mut w: Vec<usize> = wins.keys().map(|v| v.parse().unwrap()).collect();
-                            w.sort();
-                            let w: Vec<String> = w.iter().map(|v| v.to_string()).collect();
-        
-                            for i in 0..w.len() {
-                                draw_line(&mut cols, i as f32 * 60. / w.len() as f32, 60., if wins[&w[i]].starts_with("ct_") { COLOR_CT } else { COLOR_T }, BlendMode::Mix);
-                            }
-                        }
-                    } else if mvp {
-                        do_rainbow(&mut cols, time_now, 1., 1.);
-                    } else if let Some(team) = &round.win_team {
-                        fill(&mut cols, if team == "CT" { COLOR_CT } else { COLOR_T }, 1.);
-                    } else {
-                        if let Some(player) = &state.player {
-                            if let Some((_k, w)) = state.active_weapon() {
-                                if w.r#type == "Knife" {
-                                    let knife_time = (now - knife_start).as_secs_f64();
-                                    let cycle = (knife_time % 1.321 + 1.321) % 1.321;
-                                    let amt = if cycle < 0.25 {
-                                        0.5 - cycle * 2.
-                                    } else if cycle < 0.5 {
-                                        0.5 - cycle
-                                    } else {
-                                        0.
-                                    };
-                
-                                    fill(&mut cols, Color(0.2, 0., 0.), amt as f32);
-                                } else if w.r#type == "C4" {
-                                    let c4_time = (now - knife_start).as_secs_f64() / 0.25;
-                                    let cycle = (c4_time % 1. + 1.) % 1.;
-                                    let amt = if cycle < 0.5 {
-                                        cycle * 2.
-                                    } else {
-                                        2. - cycle * 2.
-                                    } * 0.75 + 0.25;
-                
-                                    fill(&mut cols, Color(0.1, 0.1, 0.), amt as f32);
-                                } else if let Some(ammo_clip) = w.ammo_clip {
-                                    if let Some(state) = &player.state {
-                                        let ammo = (ammo_clip as f64 / w.ammo_clip_max.unwrap() as f64) as f32;
-                                        let health = state.health / 100.;
-                                        let armor = state.armor / 100.;
-                    
-                                        let len = cols.len();
-                                        draw_line(&mut cols, 0., len as f32 * ammo, Color(0.5, 0., 0.), BlendMode::Add);
-                                        draw_line(&mut cols, 0., len as f32 * health, Color(0., 0.5, 0.), BlendMode::Add);
-                                        draw_line(&mut cols, 0., len as f32 * armor, Color(0., 0., 0.5), BlendMode::Add);
-                                    }
-                                } else {
-                                    println!("{}", w.r#type);
-                                }
-                            }
-                        }
-                    }
-                }
+    #[test]
+    fn writes_retry_after_timeouts() {
+        let cols = vec![Color(0.2, 0.4, 0.6); 5];
+        let mut expected = Vec::new();
+        send_frame(&mut expected, &mut Vec::new(), &cols, &mut Vec::new(), write_options(), false).unwrap();
+
+        let flaky = FlakyWriter { written: Vec::new(), timeouts: 1, attempts: 0, max_write: 4 };
+        let mut w = RetryWriter { inner: flaky, retries: WRITE_RETRIES };
+        send_frame(&mut w, &mut Vec::new(), &cols, &mut Vec::new(), write_options(), false).unwrap();
+        assert_eq!(w.inner.written, expected);
+
+        let stuck = FlakyWriter { written: Vec::new(), timeouts: usize::MAX, attempts: 0, max_write: 4 };
+        let mut w = RetryWriter { inner: stuck, retries: WRITE_RETRIES };
+        let err = send_frame(&mut w, &mut Vec::new(), &cols, &mut Vec::new(), write_options(), false).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(w.inner.attempts, WRITE_RETRIES + 1);
+        assert!(w.inner.written.is_empty());
+    }
+
+
+    #[test]
+    fn team_color_follows_player_team() {
+        let team_of = |team: serde_json::Value| {
+            let mut body = live_json("Rifle", 30);
+            body["player"]["team"] = team;
+            serde_json::from_value::<GameState>(body).unwrap().team()
+        };
+        assert_eq!(team_of("CT".into()), Some(Team::CT));
+        assert_eq!(team_of("T".into()), Some(Team::T));
+        assert_eq!(team_of("SPECTATOR".into()), None);
+        assert_eq!(team_of(serde_json::Value::Null), None);
+
+        let config = Config { team_tint: 0.5, ..Config::default() };
+        let ctx = RenderCtx { team_color: team_of("T".into()).map(Team::color), ..render_ctx(&config, DisplayState::Live) };
+        assert!(ctx.tint(Color::black()).approx_eq(&(0.5 * palette::T), 1e-6));
+        let untinted = Config::default();
+        let ctx = RenderCtx { team_color: Some(palette::T), ..render_ctx(&untinted, DisplayState::Live) };
+        assert_eq!(ctx.tint(palette::HEALTH), palette::HEALTH);
+    }
+
+
+    #[test]
+    fn strip_follows_primary_provider() {
+        let config = Mutex::new(Config { primary_provider: Some("1".to_string()), ..Config::default() });
+        let (publisher, _events) = publisher();
+        let from = |steamid: &str, weapon_type: &str| {
+            let mut body = live_json(weapon_type, 30);
+            body["provider"]["steamid"] = steamid.into();
+            body["provider"]["name"] = format!("client {}", steamid).into();
+            body
+        };
+        let weapon = || publisher.latest.borrow().active_weapon().unwrap().1.r#type.clone();
+
+        post(&from("1", "Rifle"), &config, &publisher);
+        post(&from("2", "Pistol"), &config, &publisher);
+        assert_eq!(weapon(), "Rifle");
+        post(&from("1", "Shotgun"), &config, &publisher);
+        post(&from("2", "Knife"), &config, &publisher);
+        assert_eq!(weapon(), "Shotgun");
+
+        config.lock().unwrap().primary_provider = Some("client 2".to_string());
+        post(&from("2", "Knife"), &config, &publisher);
+        assert_eq!(weapon(), "Knife");
+    }
+
+
+    #[test]
+    fn player_state_is_clamped() {
+        let player = |health: f32, armor: f32, flashed: f32| {
+            let mut body = live_json("Rifle", 30);
+            body["player"]["state"]["health"] = health.into();
+            body["player"]["state"]["armor"] = armor.into();
+            body["player"]["state"]["flashed"] = flashed.into();
+            let state: GameState = serde_json::from_value(body).unwrap();
+            let player = state.player.unwrap().state.unwrap();
+            (player.health_fraction(), player.armor_fraction(), player.flash_amount())
+        };
+        assert_eq!(player(-20., 250., 1000.), (0., 1., 255.));
+        assert_eq!(player(1e9, -1e9, -5.), (1., 0., 0.));
+        assert_eq!(player(50., 25., 100.), (0.5, 0.25, 100.));
+    }
+
+
+    #[test]
+    fn empty_state_shows_configured_idle() {
+        let state = GameState::default();
+        let config = Config { idle: IdleEffect::Solid { color: Color(0., 0.1, 0.3) }, ..Config::default() };
+        let display = DisplayState::from_state(&state, false, &config.themes);
+        assert_eq!(display, DisplayState::Idle);
+        assert_colors_approx(&render(&state, &render_ctx(&config, display), 6), &[Color(0., 0.1, 0.3); 6], 1e-6);
+
+        let off = Config { idle: IdleEffect::Off, ..Config::default() };
+        assert_colors_approx(&render(&state, &render_ctx(&off, display), 6), &[Color::black(); 6], 0.);
+    }
+
+
+    #[test]
+    fn draw_line_wraps_around_the_strip() {
+        let wrapped = |from: f32, to: f32| {
+            let mut cols = vec![Color::black(); 8];
+            draw_line_wrapped(&mut cols, from, to, 0.5 * Color::white(), BlendMode::Add);
+            cols.iter().map(|c| c.0).collect::<Vec<f32>>()
+        };
+        assert_eq!(wrapped(6., 10.), [0.5, 0.5, 0., 0., 0., 0., 0.5, 0.5]);
+        assert_eq!(wrapped(-2., 1.), [0.5, 0., 0., 0., 0., 0., 0.5, 0.5]);
+        assert_eq!(wrapped(-10., -7.), [0.5, 0., 0., 0., 0., 0., 0.5, 0.5]);
+        assert_eq!(wrapped(3., 30.), [0.5; 8]);
+        assert_eq!(wrapped(2., 2.), [0.; 8]);
+    }
+
+
+    #[test]
+    fn quick_kills_stack_pulses() {
+        let config = Config { idle: IdleEffect::Off, ..Config::default() };
+        let state = GameState::default();
+        let lit = |kills: &[f64]| {
+            let mut effects = EffectState::default();
+            for &time in kills {
+                effects.handle_event(EventType::Kill, time, &config, &state);
             }
+            let ctx = RenderCtx { time: 0.2, reactions: &effects.reactions, ..render_ctx(&config, DisplayState::Idle) };
+            render(&state, &ctx, 4)[0]
+        };
+        let first = lit(&[0.]);
+        let second = lit(&[0.1]);
+        assert!(first.0 > 0. && second.0 > 0.);
+        assert!(lit(&[0., 0.1]).approx_eq(&(first + second), 1e-5));
+    }
 
-            if let Some((event, time)) = &last_event {
-                let since = time_now - time;
-                match event {
-                    EventType::Shoot => {
-                        fill(&mut cols, Color(1., 1., 0.25), (1.0 - since * 8.).max(0.) as f32);
-                        if since > 0.125 {
-                            last_event = None;
-                        }
-                    },
-                    EventType::Death => {
-                        clear(&mut cols);
-                        fill(&mut cols, Color(1., 0., 0.), (1.0 - since * 0.25).max(0.) as f32);
-                        if since > 4. {
-                            last_event = None;
-                        }
-                    },
-                    EventType::Kill => {
-                        fill(&mut cols, Color(1., 1., 0.), (1.0 - since).max(0.) as f32);
-                        if since > 1. {
-                            last_event = None;
-                        }
-                    },
-                    _ => (),
-                }
+
+    #[test]
+    fn firmware_gamma_frames_use_gamma_opcodes() {
+        let options = WriteOptions { firmware_gamma: true, ..write_options() };
+        let cols = vec![Color(0.5, 0.25, 1.); 3];
+        let mut out = Vec::new();
+        send_frame(&mut out, &mut Vec::new(), &cols, &mut Vec::new(), options, false).unwrap();
+        assert_eq!(out, [6, 0, 64, 128, 255, 64, 128, 255, 64, 128, 255, 0, 0]);
+
+        // Diffed frames keep the gamma variant and never fall back to FillRange
+        let options = WriteOptions { diff: true, ..options };
+        let mut sent = Vec::new();
+        let mut cols = vec![Color::white(); 20];
+        send_frame(&mut Vec::new(), &mut Vec::new(), &cols, &mut sent, options, false).unwrap();
+        cols[4] = Color(0.5, 0.25, 1.);
+        cols[5] = Color(0.5, 0.25, 1.);
+        let mut out = Vec::new();
+        send_frame(&mut out, &mut Vec::new(), &cols, &mut sent, options, false).unwrap();
+        assert_eq!(out, [3, 0, 4, 0, 128, 64, 255, 3, 0, 5, 0, 128, 64, 255, 0, 0]);
+    }
+
+
+    #[test]
+    fn black_frames_send_clear() {
+        let mut out = Vec::new();
+        send_frame(&mut out, &mut Vec::new(), &[Color::black(); 10], &mut Vec::new(), write_options(), false).unwrap();
+        assert_eq!(out, [1, 0, 0, 0]);
+
+        let mut cols = [Color::black(); 10];
+        cols[9] = Color(0., 0., 0.01);
+        let mut out = Vec::new();
+        send_frame(&mut out, &mut Vec::new(), &cols, &mut Vec::new(), write_options(), false).unwrap();
+        let mut expected = encode(&Instruction::SetPixels(&cols));
+        expected.extend(encode(&Instruction::Show));
+        assert_eq!(out, expected);
+    }
+
+
+    #[test]
+    fn weapon_categories() {
+        let cases = [
+            ("Knife", WeaponCategory::Knife),
+            ("Pistol", WeaponCategory::Pistol),
+            ("Submachine Gun", WeaponCategory::Smg),
+            ("Rifle", WeaponCategory::Rifle),
+            ("SniperRifle", WeaponCategory::Sniper),
+            ("Shotgun", WeaponCategory::Shotgun),
+            ("Machine Gun", WeaponCategory::Machinegun),
+            ("Grenade", WeaponCategory::Grenade),
+            ("C4", WeaponCategory::C4),
+            ("StackableItem", WeaponCategory::Other),
+            ("rifle", WeaponCategory::Other),
+            ("", WeaponCategory::Other),
+        ];
+        for (weapon_type, expected) in cases {
+            let state = live_state(weapon_type, 30);
+            assert_eq!(state.active_weapon().unwrap().1.category(), expected, "{:?}", weapon_type);
+        }
+    }
+
+
+    #[test]
+    fn round_win_wipe_advances() {
+        let config = Config::default();
+        let mut body = live_json("Rifle", 30);
+        body["round"] = serde_json::json!({ "phase": "over", "win_team": "T" });
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let display = DisplayState::from_state(&state, false, &config.themes);
+        assert_eq!(display, DisplayState::RoundOver(Team::T));
+
+        // T wipes in from the far end
+        let lit_at = |since: f64| {
+            let ctx = RenderCtx { time: 10. + since, round_over: Some((Team::T, 10.)), ..render_ctx(&config, display) };
+            render(&state, &ctx, 8).iter().map(|c| c.approx_eq(&palette::T, 1e-5)).collect::<Vec<bool>>()
+        };
+        let front = |lit: usize| (0..8).map(|i| i >= 8 - lit).collect::<Vec<bool>>();
+        assert_eq!(lit_at(0.), front(0));
+        assert_eq!(lit_at(WIN_WIPE_TIME / 4.), front(2));
+        assert_eq!(lit_at(WIN_WIPE_TIME / 2.), front(4));
+        assert_eq!(lit_at(WIN_WIPE_TIME), front(8));
+        assert_eq!(lit_at(5.), front(8));
+    }
+
+
+    #[test]
+    fn flash_uses_configured_blend_mode() {
+        let state = live_state("Rifle", 30);
+        let flashed = |blend: BlendMode, flashed: f32| {
+            let mut config = Config::default();
+            config.effects.flashbang = Color::white();
+            config.effects.flashbang_blend = blend;
+            let ctx = RenderCtx { health: 1., flashed, ..render_ctx(&config, DisplayState::Live) };
+            render(&state, &ctx, 4)
+        };
+        let hud = flashed(BlendMode::Mix, 0.);
+        let half = 0.5 * Color::white();
+        let expect = |f: &dyn Fn(Color) -> Color| hud.iter().map(|&c| f(c)).collect::<Vec<Color>>();
+
+        assert_colors_approx(&flashed(BlendMode::Mix, 127.5), &expect(&|c| 0.5 * c + half), 1e-5);
+        assert_colors_approx(&flashed(BlendMode::Add, 127.5), &expect(&|c| c + half), 1e-5);
+        assert_colors_approx(&flashed(BlendMode::Replace, 127.5), &expect(&|_| half), 1e-5);
+    }
+
+
+    #[test]
+    fn health_drops_become_damage_or_death() {
+        let health_change = |from: i32, to: i32| {
+            let mut body = live_json("Rifle", 30);
+            body["player"]["state"]["health"] = to.into();
+            body["previously"] = serde_json::json!({ "player": { "state": { "health": from } } });
+            diff(body)
+        };
+        assert_eq!(health_change(100, 73), [EventType::TookDamage(27)]);
+        assert_eq!(health_change(50, 49), [EventType::TookDamage(1)]);
+        assert_eq!(health_change(40, 0), [EventType::Death]);
+        assert_eq!(health_change(40, 100), []);
+    }
+
+
+    #[test]
+    fn team_color_follows_side_swap() {
+        let config = Mutex::new(Config::default());
+        let themes = PhaseThemes::default();
+        let (publisher, _events) = publisher();
+        let frame = |phase: &str, team: &str| {
+            let mut body = live_json("Rifle", 30);
+            body["map"]["phase"] = phase.into();
+            body["player"]["team"] = team.into();
+            post(&body, &config, &publisher);
+            let state = publisher.latest.borrow().clone();
+            (DisplayState::from_state(&state, false, &themes), state.team().map(Team::color))
+        };
+        assert_eq!(frame("live", "CT"), (DisplayState::Live, Some(palette::CT)));
+        assert_eq!(frame("intermission", "CT").0, DisplayState::Intermission);
+        assert_eq!(frame("intermission", "T").0, DisplayState::Intermission);
+        assert_eq!(frame("live", "T"), (DisplayState::Live, Some(palette::T)));
+    }
+
+
+    #[test]
+    fn freezetime_mode_selects_display() {
+        let mut body = live_json("Rifle", 30);
+        body["round"]["phase"] = "freezetime".into();
+        body["map"]["team_ct"]["score"] = 4.into();
+        body["player"]["state"]["money"] = 8000.into();
+        body["map"]["round_wins"] = serde_json::json!({ "1": "ct_win_elimination" });
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let frame = |mode: &str| {
+            let config: Config = serde_json::from_value(serde_json::json!({ "freezetime": mode, "rounds_to_win": 8 })).unwrap();
+            render(&state, &render_ctx(&config, DisplayState::Freezetime), 16)
+        };
+
+        assert_colors_approx(&frame("round_wins"), &[palette::CT; 16], 1e-5);
+        let mut scores = vec![palette::CT; 4];
+        scores.resize(16, Color::black());
+        assert_colors_approx(&frame("scores"), &scores, 1e-5);
+        let mut money = vec![palette::MONEY; 8];
+        money.resize(16, Color::black());
+        assert_colors_approx(&frame("money"), &money, 1e-5);
+
+        assert!(serde_json::from_value::<Config>(serde_json::json!({ "freezetime": "kills" })).is_err());
+    }
+
+
+    #[test]
+    fn new_round_cue_plays_once() {
+        let round = |phase: &str, previously: serde_json::Value| {
+            let mut body = live_json("Rifle", 30);
+            body["round"]["phase"] = phase.into();
+            body["previously"] = previously;
+            body
+        };
+        let new_round = diff(round("freezetime", serde_json::json!({ "round": { "phase": "over" } })));
+        assert_eq!(new_round, [EventType::NewRound]);
+        assert!(diff(round("freezetime", serde_json::json!({}))).is_empty());
+        assert!(diff(round("live", serde_json::json!({ "round": { "phase": "freezetime" } }))).is_empty());
+
+        let config = Config::default();
+        let state = live_state("Rifle", 30);
+        let mut effects = EffectState::default();
+        effects.handle_event(EventType::NewRound, 0., &config, &state);
+        assert_eq!(effects.reactions.len(), 1);
+        let cue = effects.reactions[0].reaction.duration(&config.kill_chase);
+        assert!(cue > 0. && cue < 1.);
+        effects.expire(cue + 0.01, &config);
+        assert!(effects.reactions.is_empty());
+    }
+
+
+    #[test]
+    fn steamid_change_skips_only_one_update() {
+        let update = |kills: i32, previously: serde_json::Value| {
+            let mut body = live_json("Rifle", 30);
+            body["player"]["steamid"] = "2".into();
+            body["player"]["match_stats"] = serde_json::json!({ "assists": 0, "deaths": 0, "kills": kills, "mvps": 0, "score": 0 });
+            body["previously"] = serde_json::json!({ "player": previously });
+            serde_json::from_value::<GameState>(body).unwrap()
+        };
+        let mut state = GameState::default();
+        let mut events = Vec::new();
+
+        apply_update(&mut state, update(3, serde_json::json!({ "steamid": "1", "match_stats": { "kills": 0 } })), &mut events);
+        assert!(events.is_empty());
+        apply_update(&mut state, update(4, serde_json::json!({ "match_stats": { "kills": 3 } })), &mut events);
+        assert_eq!(events, [EventType::Kill]);
+    }
+
+
+    #[test]
+    fn holding_and_planted_c4_differ() {
+        let config = Config::default();
+        let holding = live_state("C4", 0);
+        assert_eq!(holding.bomb_carrier(), Some(BombCarrier::Holding));
+        let ctx = RenderCtx { time: 0.5, ..render_ctx(&config, DisplayState::Live) };
+        assert_colors_approx(&render(&holding, &ctx, 8), &[0.75 * palette::C4; 8], 1e-5);
+
+        let mut body = live_json("Rifle", 30);
+        body["round"]["bomb"] = "planted".into();
+        let planted: GameState = serde_json::from_value(body).unwrap();
+        let ctx = RenderCtx { time: 0.5, planted_time: Some(0.), ..render_ctx(&config, DisplayState::Live) };
+        let cols = render(&planted, &ctx, 8);
+        assert_colors_approx(&cols[6..], &[palette::BOMB; 2], 0.);
+        assert!(cols[..6].iter().all(|c| !c.approx_eq(&(0.75 * palette::C4), 0.05)));
+    }
+
+
+    #[test]
+    fn knife_and_c4_phases_are_independent() {
+        let config = Config::default();
+        let knife = live_state("Knife", 0);
+        let c4 = live_state("C4", 0);
+        let frame = |state: &GameState, time: f64, knife_time: f64| {
+            let ctx = RenderCtx { time, knife_time, ..render_ctx(&config, DisplayState::Live) };
+            render(state, &ctx, 4)
+        };
+
+        // Knife -> C4: the pulse follows the clock, not when the knife was drawn
+        assert_eq!(frame(&c4, 2.3, 0.), frame(&c4, 2.3, 1.7));
+        assert_ne!(frame(&c4, 2.3, 0.), frame(&c4, 2.55, 0.));
+        // C4 -> knife: the shimmer restarts from the draw regardless of the clock
+        assert_eq!(frame(&knife, 2.3, 0.1), frame(&knife, 9.8, 0.1));
+        assert_ne!(frame(&knife, 2.3, 0.1), frame(&knife, 2.3, 0.3));
+    }
+
+
+    #[test]
+    fn configured_bar_colors_are_rendered() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "health_color": [0.2, 0.4, 0.6],
+            "armor_color": [0.6, 0.2, 0.0],
+            "weapon_colors": { "Rifle": [0.0, 0.8, 0.8] },
+            "bar_brightness": 0.5,
+        })).unwrap();
+        let state = live_state("Rifle", 30);
+        let bar = |health: f32, armor: f32, ammo: f32| {
+            let ctx = RenderCtx { health, armor, ammo, ..render_ctx(&config, DisplayState::Live) };
+            render(&state, &ctx, 4)
+        };
+        assert_colors_approx(&bar(1., 0., 0.), &[Color(0.1, 0.2, 0.3); 4], 1e-6);
+        assert_colors_approx(&bar(0., 1., 0.), &[Color(0.3, 0.1, 0.); 4], 1e-6);
+        assert_colors_approx(&bar(0., 0., 1.), &[Color(0., 0.4, 0.4); 4], 1e-6);
+    }
+
+
+    struct RecordingOutput(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Output for RecordingOutput {
+        fn write_frame(&mut self, _cols: &[Color], _options: WriteOptions) -> std::io::Result<()> {
+            self.0.lock().unwrap().push("frame");
+            Ok(())
+        }
+
+        fn clear(&mut self) -> std::io::Result<()> {
+            self.0.lock().unwrap().push("clear");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn power_switch_stops_rendering() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let config = Config { fps: 200., ..Config::default() };
+        let (publisher, events) = publisher();
+        let status = lights_status();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let lights = tokio::spawn(do_lights(
+            vec![("test".to_string(), Box::new(RecordingOutput(log.clone())))],
+            Arc::new(Mutex::new(config)),
+            publisher.latest.clone(),
+            events,
+            shutdown.clone(),
+            status.clone(),
+            None,
+        ));
+        let settle = || tokio::time::delay_for(Duration::from_millis(100));
+        let take = || std::mem::take(&mut *log.lock().unwrap());
+
+        settle().await;
+        assert!(take().contains(&"frame"));
+
+        let req = Request::post("/power?off").body(Body::empty()).unwrap();
+        serve(req, Config::default(), &status).await;
+        assert!(!status.enabled.load(Ordering::SeqCst));
+        settle().await;
+        let off = take();
+        assert_eq!(off.last(), Some(&"clear"));
+        assert_eq!(off.iter().filter(|&&entry| entry == "clear").count(), 1);
+        settle().await;
+        assert_eq!(take(), Vec::<&str>::new());
+
+        let req = Request::post("/power").body(Body::empty()).unwrap();
+        serve(req, Config::default(), &status).await;
+        assert!(status.enabled.load(Ordering::SeqCst));
+        settle().await;
+        assert!(take().contains(&"frame"));
+
+        shutdown.store(true, Ordering::SeqCst);
+        lights.await.unwrap();
+    }
+
+
+    #[test]
+    fn round_wins_are_ordered_and_tolerant() {
+        let mut body = live_json("Rifle", 30);
+        body["map"]["round_wins"] = serde_json::json!({
+            "12": "t_win_time",
+            "3": "ct_win_bomb",
+            "7": "t_win_elimination",
+            "x": "ct_win_elimination",
+            "5": "draw",
+            "-1": "t_win_bomb",
+        });
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let wins: Vec<(u32, Team)> = state.map.unwrap().round_wins.iter().map(|win| (win.round, win.team)).collect();
+        assert_eq!(wins, [(3, Team::CT), (7, Team::T), (12, Team::T)]);
+
+        let mut body = live_json("Rifle", 30);
+        body["map"]["round_wins"] = serde_json::Value::Null;
+        let state: GameState = serde_json::from_value(body).unwrap();
+        assert!(state.map.unwrap().round_wins.is_empty());
+    }
+
+
+    #[test]
+    fn round_win_reasons() {
+        let cases = [
+            ("ct_win_elimination", Team::CT, RoundWinKind::Elimination),
+            ("t_win_elimination", Team::T, RoundWinKind::Elimination),
+            ("t_win_bomb", Team::T, RoundWinKind::Bomb),
+            ("ct_win_defuse", Team::CT, RoundWinKind::Defuse),
+            ("ct_win_time", Team::CT, RoundWinKind::Time),
+            ("t_win_surrender", Team::T, RoundWinKind::Other),
+        ];
+        for (result, team, kind) in cases {
+            let win = RoundWin::parse("1", result).unwrap();
+            assert_eq!((win.team, win.kind), (team, kind), "{}", result);
+        }
+        assert!(RoundWin::parse("1", "").is_none());
+        assert!(RoundWin::parse("1", "win_elimination").is_none());
+        assert!(RoundWin::parse("one", "ct_win_time").is_none());
+        assert_ne!(RoundWinKind::Bomb.color(Team::T), RoundWinKind::Elimination.color(Team::T));
+    }
+
+
+    #[tokio::test]
+    async fn healthz_reports_connection_and_last_update() {
+        let config = Arc::new(Mutex::new(Config::default()));
+        let (publisher, _events) = publisher();
+        let publisher = Arc::new(publisher);
+        let status = lights_status();
+        let healthz = || async {
+            let req = Request::get("/healthz").body(Body::empty()).unwrap();
+            let response = handle_http(req, config.clone(), publisher.clone(), None, None, status.clone()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let health = healthz().await;
+        assert_eq!(health["connected"], false);
+        assert!(health["last_update_secs"].is_null());
+
+        status.connected.store(true, Ordering::SeqCst);
+        post(&live_json("Rifle", 30), &config, &publisher);
+        let health = healthz().await;
+        assert_eq!(health["connected"], true);
+        assert!(health["last_update_secs"].as_f64().is_some_and(|secs| secs < 5.));
+    }
+
+
+    #[test]
+    fn byte_conversion_rounds_and_clamps() {
+        assert_eq!(to_byte(0.999), 255);
+        assert_eq!(to_byte(0.998), 254);
+        assert_eq!(to_byte((127.5 - 0.01) / 255.), 127);
+        assert_eq!(to_byte((127.5 + 0.01) / 255.), 128);
+        assert_eq!(to_byte(0.4 / 255.), 0);
+        assert_eq!(to_byte(0.6 / 255.), 1);
+        assert_eq!(to_byte(0.), 0);
+        assert_eq!(to_byte(1.), 255);
+        assert_eq!(to_byte(-0.5), 0);
+        assert_eq!(to_byte(1.5), 255);
+        assert_eq!(Color(-1., 0.5, 2.).as_byte_color(), (0, 128, 255));
+    }
+
+
+    #[test]
+    fn uniform_runs_use_fill_range() {
+        let options = WriteOptions { diff: true, ..write_options() };
+        let mut cols = vec![Color::black(); 30];
+        cols[0] = Color::white();
+        let mut sent = Vec::new();
+        send_frame(&mut Vec::new(), &mut Vec::new(), &cols, &mut sent, options, false).unwrap();
+
+        for c in &mut cols[10..16] {
+            *c = Color::red();
+        }
+        cols[20] = Color::blue();
+        let mut out = Vec::new();
+        send_frame(&mut out, &mut Vec::new(), &cols, &mut sent, options, false).unwrap();
+        let mut expected = encode(&Instruction::FillRange(10, 16, Color::red()));
+        expected.extend(encode(&Instruction::SetPixelColor(20, Color::blue())));
+        expected.extend(encode(&Instruction::Show));
+        assert_eq!(out, expected);
+        assert_eq!(out[..9], [7, 0, 10, 0, 16, 0, 255, 0, 0]);
+    }
+
+
+    #[test]
+    fn spectator_fallback_modes() {
+        let mut body = live_json("Rifle", 30);
+        body["player"].as_object_mut().unwrap().remove("state");
+        body["map"]["team_ct"]["score"] = 4.into();
+        body["map"]["team_t"]["score"] = 2.into();
+        let state: GameState = serde_json::from_value(body).unwrap();
+        let frame = |fallback: SpectatorFallback| {
+            let config = Config { spectator_fallback: fallback, rounds_to_win: 8, idle: IdleEffect::Solid { color: Color(0., 0.2, 0.) }, ..Config::default() };
+            render(&state, &render_ctx(&config, DisplayState::Live), 16)
+        };
+
+        let mut scoreboard = vec![palette::CT; 4];
+        scoreboard.resize(14, Color::black());
+        scoreboard.resize(16, palette::T);
+        assert_colors_approx(&frame(SpectatorFallback::Scoreboard), &scoreboard, 1e-5);
+        assert_colors_approx(&frame(SpectatorFallback::Idle), &[Color(0., 0.2, 0.); 16], 1e-6);
+        assert_colors_approx(&frame(SpectatorFallback::Follow), &[Color::black(); 16], 0.);
+    }
+
+
+    #[test]
+    fn weapon_switch_cue_plays_once() {
+        let mut body = live_json("Pistol", 12);
+        body["previously"] = serde_json::json!({ "player": { "weapons": { "weapon_0": { "state": "holstered" } } } });
+        assert_eq!(diff(body), [EventType::SwitchWeapon]);
+
+        let mut config = Config::default();
+        let state = live_state("Pistol", 12);
+        let mut effects = EffectState::default();
+        effects.handle_event(EventType::SwitchWeapon, 0., &config, &state);
+        assert!(effects.reactions.is_empty());
+
+        config.switch_flash.enabled = true;
+        effects.handle_event(EventType::SwitchWeapon, 0., &config, &state);
+        effects.handle_event(EventType::SwitchWeapon, 0.05, &config, &state);
+        assert_eq!(effects.reactions.len(), 1);
+        match effects.reactions[0].reaction {
+            Reaction::Flash(effect) => assert!(effect.color.approx_eq(&(config.switch_flash.brightness * config.weapon_color("Pistol")), 1e-6)),
+            _ => panic!("switch cue should be a flash"),
+        }
+    }
+
+
+    #[test]
+    fn round_end_clears_pending_flashes() {
+        let config = Config::default();
+        let live = live_state("Rifle", 30);
+        let mut body = live_json("Rifle", 30);
+        body["round"] = serde_json::json!({ "phase": "over", "win_team": "CT" });
+        let over: GameState = serde_json::from_value(body).unwrap();
+        let mut effects = EffectState::default();
+
+        effects.update(&live, 0., &config);
+        effects.handle_event(EventType::TookDamage(40), 0., &config, &live);
+        assert_eq!(effects.reactions.len(), 1);
+
+        // Same order as the render loop: clean up on the transition, then drain this frame's events
+        effects.update(&over, 0.1, &config);
+        assert!(effects.reactions.is_empty());
+        effects.handle_event(EventType::Kill, 0.1, &config, &over);
+        effects.update(&over, 0.2, &config);
+        let events: Vec<Option<EventType>> = effects.reactions.iter().map(|r| r.event).collect();
+        assert_eq!(events, [Some(EventType::Kill)]);
+    }
+
+
+    struct MockPort {
+        baud_rate: u32,
+        acks_at: Option<u32>,
+        broken: u32,
+        tried: Vec<u32>,
+        pending: Vec<u8>,
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"));
             }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
 
-            if let Some((event, time)) = &kill_event {
-                let since = time_now - time;
-                match event {
-                    EventType::KnifeKill => {
-                        do_rainbow(&mut cols, time_now, 1., (2.0 - since * 0.5).max(0.) as f32);
-                        if since > 1. {
-                            last_event = None;
-                        }
-                    },
-                    _ => (),
-                }
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // At the wrong rate the controller only sees noise and answers with garbage
+            if buf == encode(&Instruction::Ping).as_slice() {
+                self.pending.push(if self.acks_at == Some(self.baud_rate) { PING_ACK } else { 0xfe });
             }
+            Ok(buf.len())
+        }
 
-            if let Some(player) = &state.player {
-                if let Some(state) = &player.state {
-                    if state.flashed > 0. {
-                        fill(&mut cols, Color(1., 1., 1.), state.flashed / 255.);
-                    }
-                }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BaudProbe for MockPort {
+        fn set_baud(&mut self, baud_rate: u32) -> serialport::Result<()> {
+            if baud_rate == self.broken {
+                return Err(serialport::Error::new(serialport::ErrorKind::InvalidInput, "unsupported"));
             }
+            self.baud_rate = baud_rate;
+            self.tried.push(baud_rate);
+            Ok(())
+        }
+
+        fn clear_input(&mut self) -> serialport::Result<()> {
+            self.pending.clear();
+            Ok(())
         }
-        Instruction::SetPixels(&cols).write(s).unwrap();
-        Instruction::Show.write(s).unwrap();
     }
-}
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let ps = serialport::available_ports().expect("Failed to get serial ports");
+    #[test]
+    fn baud_negotiation_picks_acknowledged_rate() {
+        let port = |acks_at: Option<u32>| MockPort { baud_rate: 9600, acks_at, broken: 0, tried: Vec::new(), pending: vec![0xaa] };
+        let bauds = [115200, 250000, 1000000];
 
-    for i in 0..ps.len() {
-        let port = &ps[i];
-        println!("{}: {}", i, match &port.port_type {
-            SerialPortType::UsbPort(info) => match &info.product {
-                Some(p) => format!("{}, ({})", p, port.port_name),
-                _ => port.port_name.clone(),
-            },
-            _ => port.port_name.clone(),
-        });
+        let mut mock = port(Some(250000));
+        assert_eq!(negotiate_baud(&mut mock, &bauds), Some(250000));
+        assert_eq!(mock.tried, [115200, 250000]);
+
+        let mut mock = port(Some(1000000));
+        mock.broken = 250000;
+        assert_eq!(negotiate_baud(&mut mock, &bauds), Some(1000000));
+        assert_eq!(mock.tried, [115200, 1000000]);
+
+        let mut mock = port(None);
+        assert_eq!(negotiate_baud(&mut mock, &bauds), None);
+        assert_eq!(mock.tried, bauds);
     }
 
-    let port_name = loop {
-        let mut s = String::new();
-        std::io::stdin().read_line(&mut s).expect("Failed to read input");
-        if let Ok(i) = s.trim().parse::<usize>() {
-            if let Some(p) = ps.get(i) {
-                break p.port_name.clone();
-            }
-            println!("No index");
-        } 
-        println!("Enter a valid index");
-    };
-    println!("Beginning to send data on {}", port_name);
 
-    let state = Arc::new(Mutex::new(GameState::default()));
-    let next_event = Arc::new(Mutex::new(Vec::new()));
+    #[test]
+    fn loss_bonus_maps_through_custom_table() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "loss_bonus_tiers": [[0.2, 0.2, 0.2], [0.5, 0.5, 0.0], [1.0, 0.0, 0.0]],
+        })).unwrap();
+        let (low, mid, high) = (Color(0.2, 0.2, 0.2), Color(0.5, 0.5, 0.), Color(1., 0., 0.));
+        assert_eq!(config.loss_bonus(-1), (1, 3, Some(low)));
+        assert_eq!(config.loss_bonus(0), (1, 3, Some(low)));
+        assert_eq!(config.loss_bonus(1), (2, 3, Some(mid)));
+        assert_eq!(config.loss_bonus(2), (3, 3, Some(high)));
+        assert_eq!(config.loss_bonus(7), (3, 3, Some(high)));
 
-    let s1 = Arc::clone(&state);
-    let e1 = Arc::clone(&next_event);
-    let s2 = Arc::clone(&state);
-    let e2 = Arc::clone(&next_event);
+        let default = Config::default();
+        assert_eq!(default.loss_bonus(0), (1, LOSS_BONUS_LEVELS, None));
+        assert_eq!(default.loss_bonus(10), (LOSS_BONUS_LEVELS, LOSS_BONUS_LEVELS, None));
+    }
 
-    std::thread::spawn(move || {
-        do_lights(&port_name, s2, e2);
-    });
 
-    if let Err(e) = hyper::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 3000))).serve(hyper::service::make_service_fn(|_conn| {
-        let s1 = Arc::clone(&s1);
-        let e1 = Arc::clone(&e1);
-        async {
-            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
-                let s1 = Arc::clone(&s1);
-                let e1 = Arc::clone(&e1);
-                handle_http(req, s1, e1)
-            }))
+    #[test]
+    fn spraying_keeps_the_shoot_overlay_bounded() {
+        let config = Config { idle: IdleEffect::Off, ..Config::default() };
+        let state = GameState::default();
+        let shoot = config.effects.shoot;
+        let mut effects = EffectState::default();
+        let mut flashes = 0;
+        for i in 0..50 {
+            let time = i as f64 * 0.02;
+            let before = effects.last_shot;
+            effects.handle_event(EventType::Shoot, time, &config, &state);
+            if effects.last_shot != before {
+                flashes += 1;
+            }
+            effects.expire(time, &config);
+            assert!(effects.reactions.len() <= 1);
+
+            let ctx = RenderCtx { time, reactions: &effects.reactions, ..render_ctx(&config, DisplayState::Idle) };
+            for c in render(&state, &ctx, 4) {
+                assert!(c.0 <= shoot.color.0 + 1e-6 && c.1 <= shoot.color.1 + 1e-6 && c.2 <= shoot.color.2 + 1e-6);
+            }
         }
-    })).await {
-        eprintln!("Server error: {}", e);
+        let cooldown_limit = (1. / config.effects.shoot_cooldown).ceil() as usize + 1;
+        assert!(flashes > 1 && flashes <= cooldown_limit, "{} flashes", flashes);
+        assert!(shoot.color.0 < 0.5);
     }
-    Ok(())
 }